@@ -2,4 +2,4 @@ set -e
 
 RUSTFLAG='-C link-arg=-s' cargo build --target wasm32-unknown-unknown --release
 mkdir -p out
-cp target/wasm32-unknown-unknown/release/*.wasm out/staking-contract.wasm
\ No newline at end of file
+cp target/wasm32-unknown-unknown/release/staking_contract.wasm out/staking-contract.wasm
\ No newline at end of file
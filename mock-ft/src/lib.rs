@@ -0,0 +1,137 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::U128;
+use near_sdk::*;
+
+const GAS_FOR_FT_ON_TRANSFER: Gas = 50_000_000_000_000;
+const GAS_FOR_RESOLVE_TRANSFER: Gas = 10_000_000_000_000;
+
+#[ext_contract(ext_receiver)]
+pub trait FungibleTokenReceiver {
+    fn ft_on_transfer(&mut self, sender_id: AccountId, amount: U128, msg: String) -> PromiseOrValue<U128>;
+}
+
+#[ext_contract(ext_self)]
+pub trait FungibleTokenResolver {
+    fn ft_resolve_transfer(&mut self, sender_id: AccountId, receiver_id: AccountId, amount: U128) -> U128;
+}
+
+// Only the parts of NEP-141 used by the staking contract
+// Registration is free and balances are plain numbers
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+#[near_bindgen]
+pub struct MockFungibleToken {
+    pub balances: LookupMap<AccountId, Balance>,
+    pub total_supply: Balance,
+}
+
+#[near_bindgen]
+impl MockFungibleToken {
+    #[init]
+    pub fn new(owner_id: AccountId, total_supply: U128) -> Self {
+        let mut token = MockFungibleToken {
+            balances: LookupMap::new(b"mock_ft".to_vec()),
+            total_supply: total_supply.0,
+        };
+        token.balances.insert(&owner_id, &total_supply.0);
+        token
+    }
+
+    #[payable]
+    pub fn storage_deposit(&mut self, account_id: Option<AccountId>) {
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        if self.balances.get(&account_id).is_none() {
+            self.balances.insert(&account_id, &0);
+        }
+    }
+
+    // Unregistered accounts cannot receive tokens, which makes transfers to them fail
+    pub fn storage_unregister(&mut self) -> bool {
+        let account_id = env::predecessor_account_id();
+        let balance = self.balances.get(&account_id).expect("Account is not registered");
+        assert_eq!(balance, 0, "Cannot unregister an account with a positive balance");
+        self.balances.remove(&account_id);
+        true
+    }
+
+    #[payable]
+    pub fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>) {
+        assert_one_yocto();
+        let sender_id = env::predecessor_account_id();
+        self.internal_transfer(&sender_id, &receiver_id, amount.0);
+        if let Some(memo) = memo {
+            env::log(format!("Memo: {}", memo).as_bytes());
+        }
+    }
+
+    #[payable]
+    pub fn ft_transfer_call(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>, msg: String) -> Promise {
+        assert_one_yocto();
+        let sender_id = env::predecessor_account_id();
+        self.internal_transfer(&sender_id, &receiver_id, amount.0);
+        if let Some(memo) = memo {
+            env::log(format!("Memo: {}", memo).as_bytes());
+        }
+
+        ext_receiver::ft_on_transfer(
+            sender_id.clone(),
+            amount,
+            msg,
+            &receiver_id,
+            0,
+            GAS_FOR_FT_ON_TRANSFER,
+        ).then(ext_self::ft_resolve_transfer(
+            sender_id,
+            receiver_id,
+            amount,
+            &env::current_account_id(),
+            0,
+            GAS_FOR_RESOLVE_TRANSFER,
+        ))
+    }
+
+    // Refund the unused amount returned by `ft_on_transfer`, and return the used amount
+    #[private]
+    pub fn ft_resolve_transfer(&mut self, sender_id: AccountId, receiver_id: AccountId, amount: U128) -> U128 {
+        let unused_amount = match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(value) => {
+                match near_sdk::serde_json::from_slice::<U128>(&value) {
+                    Ok(unused_amount) => unused_amount.0.min(amount.0),
+                    Err(_) => amount.0,
+                }
+            },
+            PromiseResult::Failed => amount.0,
+        };
+
+        let mut refund_amount = 0;
+        if unused_amount > 0 && self.balances.get(&sender_id).is_some() {
+            refund_amount = unused_amount.min(self.balances.get(&receiver_id).unwrap_or(0));
+            if refund_amount > 0 {
+                self.internal_transfer(&receiver_id, &sender_id, refund_amount);
+            }
+        }
+        U128(amount.0 - refund_amount)
+    }
+
+    pub fn ft_balance_of(&self, account_id: AccountId) -> U128 {
+        U128(self.balances.get(&account_id).unwrap_or(0))
+    }
+
+    pub fn ft_total_supply(&self) -> U128 {
+        U128(self.total_supply)
+    }
+}
+
+impl MockFungibleToken {
+    fn internal_transfer(&mut self, sender_id: &AccountId, receiver_id: &AccountId, amount: Balance) {
+        assert_ne!(sender_id, receiver_id, "Sender and receiver should be different");
+        assert!(amount > 0, "The amount should be a positive number");
+        let sender_balance = self.balances.get(sender_id).expect("Sender is not registered");
+        let receiver_balance = self.balances.get(receiver_id).expect("Receiver is not registered");
+        assert!(sender_balance >= amount, "Not enough balance");
+
+        self.balances.insert(sender_id, &(sender_balance - amount));
+        self.balances.insert(receiver_id, &(receiver_balance + amount));
+    }
+}
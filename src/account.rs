@@ -9,6 +9,17 @@ pub enum Membership {
     Companion
 }
 
+impl Membership {
+    // Multiplier of the reward of the tier, in basis points
+    pub fn reward_bps(&self) -> u32 {
+        match self {
+            Membership::Basic => BPS_DENOMINATOR,
+            Membership::Standard => 11_000,
+            Membership::Companion => 12_000,
+        }
+    }
+}
+
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct Account {
@@ -19,6 +30,20 @@ pub struct Account {
     pub unstake_start_time: Timestamp,          
     pub unstake_available_epoch: EpochHeight,   
     pub membership: Membership,                 // Upgraded field
+    pub referrer_id: Option<AccountId>,         // Account earning a share of this account's reward
+    pub reward_remainder: u128,                 // Rounding remainder of the reward, in units of `1 / REWARD_PER_SHARE_SCALE`
+    pub locked_balance: Balance,                // Part of the stake held by locked positions (see `positions.rs`)
+    pub boost_balance: Balance,                 // Extra stake earning reward, granted by the locked positions
+    pub reward_per_share_paid: u128,            // Reward per share of the pool when the reward was last settled
+    pub reward_weight: Balance,                 // Weight earning reward since then, counted in the pool total
+}
+
+impl Account {
+    // Stake earning reward: the boost of the locked positions earns as if it was staked,
+    // and the membership tier multiplies both
+    pub fn weight(&self) -> Balance {
+        mul_div(self.stake_balance + self.boost_balance, self.membership.reward_bps() as Balance, BPS_DENOMINATOR as Balance)
+    }
 }
 
 #[derive(Deserialize, Serialize)]
@@ -33,6 +58,9 @@ pub struct AccountJson {
     pub unstake_available_epoch: EpochHeight,
     pub current_epoch: EpochHeight,
     pub membership: Membership,                 // Upgraded field
+    pub referrer_id: Option<AccountId>,
+    pub locked_balance: Balance,
+    pub boost_balance: Balance,
 }
 
 // To cast from Account to Json
@@ -48,6 +76,16 @@ impl AccountJson {
             unstake_available_epoch: account.unstake_available_epoch,
             current_epoch: env::epoch_height(),
             membership: account.membership,
+            referrer_id: account.referrer_id,
+            locked_balance: account.locked_balance,
+            boost_balance: account.boost_balance,
         }
     }
-}
\ No newline at end of file
+}
+#[derive(Deserialize, Serialize)]
+#[serde(crate="near_sdk::serde")]
+pub struct ReferralJson {
+    pub account_id: AccountId,
+    pub num_referees: u64,
+    pub referral_earnings: U128,
+}
@@ -0,0 +1,139 @@
+use crate::*;
+use near_sdk::serde_json::json;
+
+pub const BATCH_GAS_RESERVE: Gas = 10_000_000_000_000;
+pub const AIRDROP_WITHDRAW_CALLBACK_GAS: Gas = 10_000_000_000_000;
+
+#[derive(Deserialize, Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AirdropCredit {
+    pub account_id: AccountId,
+    pub amount: U128,
+}
+
+// Treasury manager batches, for airdrops and bulk onboarding
+// Each call processes its list from `from_index` until the gas runs out, and returns the index to resume from
+// The list is done when the returned index is its length
+#[near_bindgen]
+impl StakingContract {
+    // Register the accounts of the list which are not registered yet, the attached deposit pays their storage
+    // The caller is recorded as the payer, which gets the storage back when an account is closed
+    #[payable]
+    pub fn batch_register(&mut self, account_ids: Vec<AccountId>, from_index: Option<u64>) -> u64 {
+        self.assert_role(Role::TreasuryManager);
+        let payer_id = env::predecessor_account_id();
+        let storage_usage_before = env::storage_usage();
+        let from_index = from_index.unwrap_or(0);
+        let mut next_index = from_index;
+        let mut num_registered = 0;
+        for account_id in account_ids.iter().skip(from_index as usize) {
+            if env::used_gas() + BATCH_GAS_RESERVE > env::prepaid_gas() {
+                break;
+            }
+            next_index += 1;
+            if self.accounts.get(account_id).is_some() {
+                continue;
+            }
+            if self.allowlist_enabled && !self.allowlist.contains(account_id) {
+                env::log(format!("Account {} is skipped, it is not in the allowlist", account_id).as_bytes());
+                continue;
+            }
+            let account_storage_usage_before = env::storage_usage();
+            self.internal_create_account(account_id.clone(), None);
+            self.internal_record_storage_charge(account_id, StoragePayer::Account(payer_id.clone()), account_storage_usage_before);
+            num_registered += 1;
+        }
+
+        refund_deposit(env::storage_usage() - storage_usage_before);
+        emit_event("accounts_registered", json!({
+            "num_registered": num_registered,
+            "from_index": from_index,
+            "next_index": next_index,
+        }));
+        next_index
+    }
+
+    // Stake the airdrop balance for the accounts of the list, as if they had deposited it
+    // Accounts which cannot stake (unregistered, denied or locked) are skipped, the part over the stake caps is not credited
+    pub fn airdrop_stake(&mut self, credits: Vec<AirdropCredit>, from_index: Option<u64>) -> u64 {
+        self.assert_role(Role::TreasuryManager);
+        assert!(!self.is_paused(), "Contract is paused");
+        let from_index = from_index.unwrap_or(0);
+        let mut next_index = from_index;
+        let mut total_credited = 0;
+        for credit in credits.iter().skip(from_index as usize) {
+            if env::used_gas() + BATCH_GAS_RESERVE > env::prepaid_gas() {
+                break;
+            }
+            next_index += 1;
+            if !self.internal_can_receive_airdrop(&credit.account_id) {
+                env::log(format!("Account {} is skipped, it cannot stake", credit.account_id).as_bytes());
+                continue;
+            }
+            assert!(credit.amount.0 <= self.airdrop_balance, "Airdrop balance is too low");
+            let unused_amount = self.internal_stake_deposit(credit.account_id.clone(), credit.amount.0);
+            self.airdrop_balance -= credit.amount.0 - unused_amount;
+            total_credited += credit.amount.0 - unused_amount;
+        }
+
+        emit_event("airdrop_staked", json!({
+            "amount": U128(total_credited),
+            "from_index": from_index,
+            "next_index": next_index,
+            "airdrop_balance": U128(self.airdrop_balance),
+        }));
+        next_index
+    }
+
+    // Transfer airdrop balance which is not credited, e.g. after the airdrop is over
+    // The balance is restored if the transfer fails
+    #[payable]
+    pub fn withdraw_airdrop_balance(&mut self, amount: U128, receiver_id: AccountId) -> Promise {
+        assert_one_yocto();
+        self.assert_role(Role::TreasuryManager);
+        assert!(amount.0 > 0 && amount.0 <= self.airdrop_balance, "Cannot withdraw more than the airdrop balance {}", self.airdrop_balance);
+        self.airdrop_balance -= amount.0;
+        self.pending_transfers += amount.0;
+        emit_event("airdrop_withdrawn", json!({
+            "receiver_id": receiver_id,
+            "amount": amount,
+            "airdrop_balance": U128(self.airdrop_balance),
+        }));
+
+        ext_ft::ft_transfer(
+            receiver_id,
+            amount,
+            Some("Airdrop withdrawal from staking contract".to_string()),
+            &self.ft_contract_id,
+            1,
+            FT_TRANSFER_GAS
+        ).then(ext_self::ft_airdrop_withdraw_callback(
+            amount,
+            &env::current_account_id(),
+            0,
+            AIRDROP_WITHDRAW_CALLBACK_GAS
+        ))
+    }
+
+    #[private]
+    pub fn ft_airdrop_withdraw_callback(&mut self, amount: U128) -> U128 {
+        assert_eq!(env::promise_results_count(), 1, "Too many promise results");
+        self.pending_transfers -= amount.0;
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_value) => amount,
+            PromiseResult::Failed => {
+                self.airdrop_balance += amount.0;
+                emit_event("airdrop_withdraw_failed", json!({
+                    "amount": amount,
+                    "airdrop_balance": U128(self.airdrop_balance),
+                }));
+                U128(0)
+            },
+        }
+    }
+
+    pub fn get_airdrop_balance(&self) -> U128 {
+        U128(self.airdrop_balance)
+    }
+}
@@ -0,0 +1,188 @@
+use crate::*;
+use near_sdk::serde_json::json;
+
+pub const CLOSE_ACCOUNT_CALLBACK_GAS: Gas = 20_000_000_000_000;
+
+#[near_bindgen]
+impl StakingContract {
+    // Exit everything and unregister
+    // The stake is unstaked (and in vesting mode, the pending reward is vested). While these funds are locked,
+    // the call returns and the next ones fail until the lock is over
+    // Then the unstaked tokens, the reward and the vested reward are transferred at once, the account is removed
+    // and the storage charged for its registration goes back to its payer. If the transfer fails, the account is
+    // restored as it was
+    // The referees are detached by every call, after paying their referral reward until then, so no referral reward
+    // can be paid to the account while its transfer is in flight
+    #[payable]
+    pub fn close_account(&mut self) -> PromiseOrValue<U128> {
+        assert_one_yocto();
+        assert!(!self.is_paused(), "Contract is paused");
+        let account_id = env::predecessor_account_id();
+        self.assert_not_locked(&account_id);
+        assert!(self.accounts.get(&account_id).is_some(), "Account not found");
+        self.internal_detach_referees(&account_id);
+        let upgradable_account = self.accounts.get(&account_id).unwrap();
+        let account = Account::from(upgradable_account);
+        assert_eq!(account.locked_balance, 0, "Unlock or transfer the locked positions first");
+
+        let mut has_progressed = false;
+        if account.stake_balance > 0 {
+            self.internal_unstake(account_id.clone(), account.stake_balance);
+            has_progressed = true;
+        }
+
+        let mut account = Account::from(self.accounts.get(&account_id).unwrap());
+        self.internal_update_account_reward(&mut account);
+        let epoch = env::epoch_height();
+        if let Some(vesting) = self.config.vesting {
+            if account.pre_reward > 0 {
+                // The reward of a later call vests until the end of the schedule, so that the close is not delayed again
+                match self.internal_get_vesting_schedule(&account_id).and_then(|schedule| schedule.end_epoch()) {
+                    Some(end_epoch) => {
+                        let mut schedule = self.internal_get_vesting_schedule(&account_id).unwrap();
+                        let cliff_epoch = end_epoch.min(epoch + vesting.cliff_epochs);
+                        schedule.add_tranche(account.pre_reward, epoch, cliff_epoch, end_epoch);
+                        self.internal_save_vesting_schedule(&account_id, &schedule);
+                    },
+                    None => {
+                        self.internal_vest_reward(&account_id, account.pre_reward, &vesting);
+                        has_progressed = true;
+                    },
+                }
+                account.pre_reward = 0;
+            }
+        }
+
+        let mut schedule = self.internal_get_vesting_schedule(&account_id);
+        if let Some(schedule) = schedule.as_mut() {
+            schedule.release(epoch);
+        }
+        let mut available_epoch = epoch;
+        if account.unstake_balance > 0 {
+            available_epoch = available_epoch.max(account.unstake_available_epoch);
+        }
+        if let Some(end_epoch) = schedule.as_ref().and_then(|schedule| schedule.end_epoch()) {
+            available_epoch = available_epoch.max(end_epoch);
+        }
+        if available_epoch > epoch {
+            assert!(has_progressed, "Funds are still locked until epoch {}", available_epoch);
+            self.internal_save_account(&account_id, account);
+            emit_event("account_closing", json!({ "account_id": account_id, "available_epoch": available_epoch }));
+            return PromiseOrValue::Value(U128(0));
+        }
+
+        let vested = schedule.map_or(0, |schedule| schedule.unlocked_balance);
+        let amount = account.unstake_balance + account.pre_reward + vested;
+        let entry_storage_usage = self.internal_remove_account(&account_id);
+        self.internal_remove_vesting_schedule(&account_id);
+        let storage_charge = self.internal_take_storage_charge(&account_id, entry_storage_usage);
+        self.total_unstaking -= account.unstake_balance;
+
+        if amount == 0 {
+            self.internal_refund_storage_charge(&account_id, &storage_charge);
+            return PromiseOrValue::Value(U128(0));
+        }
+        self.internal_lock_account(&account_id, PendingOperation::CloseAccount);
+        self.pending_storage_refunds += storage_charge.amount.0;
+        self.pending_transfers += amount;
+
+        ext_ft::ft_transfer(
+            account_id.clone(),
+            U128(amount),
+            Some("Close account from staking contract".to_string()),
+            &self.ft_contract_id,
+            1,
+            FT_TRANSFER_GAS
+        ).then(ext_self::ft_close_account_callback(
+            account_id,
+            account,
+            U128(vested),
+            storage_charge,
+            &env::current_account_id(),
+            0,
+            CLOSE_ACCOUNT_CALLBACK_GAS
+        )).into()
+    }
+
+    #[private]
+    pub fn ft_close_account_callback(&mut self, account_id: AccountId, old_account: Account, vested: U128, storage_charge: StorageCharge) -> U128 {
+        assert_eq!(env::promise_results_count(), 1, "Too many promise results");
+        self.internal_unlock_account(&account_id);
+        self.pending_storage_refunds -= storage_charge.amount.0;
+        self.pending_transfers -= old_account.unstake_balance + old_account.pre_reward + vested.0;
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_value) => {
+                self.total_withdrawn += old_account.unstake_balance;
+                self.total_paid_reward += old_account.pre_reward + vested.0;
+                self.internal_refund_storage_charge(&account_id, &storage_charge);
+                U128(old_account.unstake_balance + old_account.pre_reward + vested.0)
+            },
+            PromiseResult::Failed => {
+                self.total_unstaking += old_account.unstake_balance;
+                if let Some(account) = self.accounts.get(&account_id) {
+                    // The account was registered again after an admin cleared the pending operation:
+                    // the funds are credited to the new account, and the storage of the old one is refunded
+                    let mut account = Account::from(account);
+                    account.unstake_balance += old_account.unstake_balance;
+                    account.unstake_start_time = account.unstake_start_time.max(old_account.unstake_start_time);
+                    account.unstake_available_epoch = account.unstake_available_epoch.max(old_account.unstake_available_epoch);
+                    account.pre_reward += old_account.pre_reward;
+                    self.internal_save_account(&account_id, account);
+                    if vested.0 > 0 {
+                        let mut schedule = self.internal_get_vesting_schedule(&account_id).unwrap_or_else(VestingSchedule::new);
+                        schedule.unlocked_balance += vested.0;
+                        self.internal_save_vesting_schedule(&account_id, &schedule);
+                    }
+                    self.internal_refund_storage_charge(&account_id, &storage_charge);
+                    return U128(0);
+                }
+
+                // The restored account uses the storage which was not refunded
+                // Its referrer may have closed meanwhile, and then detached the account already
+                let mut old_account = old_account;
+                if old_account.referrer_id.as_ref().is_some_and(|referrer_id| self.accounts.get(referrer_id).is_none()) {
+                    old_account.referrer_id = None;
+                }
+                self.internal_save_account(&account_id, old_account);
+                if vested.0 > 0 {
+                    let mut schedule = VestingSchedule::new();
+                    schedule.unlocked_balance = vested.0;
+                    self.internal_save_vesting_schedule(&account_id, &schedule);
+                }
+                self.storage_charges.insert(&account_id, &storage_charge);
+                if storage_charge.payer == StoragePayer::StoragePool {
+                    self.storage_pool_usage.insert(&account_id, &storage_charge.amount.0);
+                }
+                U128(0)
+            },
+        }
+    }
+}
+
+impl StakingContract {
+    // Clear the charge recorded at registration, with the usage of the storage pool
+    // Accounts registered before the charges were recorded paid for their entry only, which is refunded to them
+    fn internal_take_storage_charge(&mut self, account_id: &AccountId, entry_storage_usage: StorageUsage) -> StorageCharge {
+        self.storage_pool_usage.remove(account_id);
+        self.storage_charges.remove(account_id).unwrap_or_else(|| StorageCharge {
+            payer: StoragePayer::Account(account_id.clone()),
+            amount: U128(env::storage_byte_cost() * Balance::from(entry_storage_usage)),
+        })
+    }
+
+    // The storage charged for the registration goes back to whoever paid it
+    // The bytes used later by the contract itself (vesting tranches, index entries) are not refunded
+    fn internal_refund_storage_charge(&mut self, account_id: &AccountId, storage_charge: &StorageCharge) {
+        let storage_refund = storage_charge.amount.0;
+        match &storage_charge.payer {
+            StoragePayer::StoragePool => self.storage_pool += storage_refund,
+            StoragePayer::Account(payer_id) => {
+                if storage_refund > 0 {
+                    Promise::new(payer_id.clone()).transfer(storage_refund);
+                }
+            },
+        }
+        emit_event("account_closed", json!({ "account_id": account_id, "storage_refund": U128(storage_refund) }));
+    }
+}
@@ -7,12 +7,49 @@ pub struct Config {
     pub reward_num: u32,        // Incentive numerator
     pub reward_denom: u64,      // Incentive denomerator
     pub num_epoch_unlock: u64,  // Number of epoch need to wait to withdraw when unstake
+    pub vesting: Option<VestingConfig>, // Vest harvested reward instead of transferring it
+    #[serde(default)]
+    pub referral_reward_bps: u32,       // Share of referees' reward paid to their referrer (basis points)
+    pub max_stake_per_account: Option<U128>,    // Cap of the stake balance of one account
+    pub max_total_stake: Option<U128>,          // Cap of the total stake of the pool
+    pub min_stake_amount: Option<U128>,         // Smaller deposits are refunded
+    pub auto_register: Option<AutoRegisterConfig>,  // Register unknown accounts on their first stake, paid by the storage pool
+    pub position: Option<PositionConfig>,       // Locked positions, disabled by default
 }
 
 // With APR 15% --> reward = 0.15*token (per year)
 // But we use the number of blocks, so APR 15%-18% is 
 impl Default for Config {
     fn default() -> Self {
-        Self { reward_num: 715, reward_denom: 10u64.pow(9), num_epoch_unlock: 1 }
+        Self {
+            reward_num: 715,
+            reward_denom: 10u64.pow(9),
+            num_epoch_unlock: 1,
+            vesting: None,
+            referral_reward_bps: 0,
+            max_stake_per_account: None,
+            max_total_stake: None,
+            min_stake_amount: None,
+            auto_register: None,
+            position: None,
+        }
     }
-}
\ No newline at end of file
+}
+
+impl Config {
+    // A config which breaks the reward math could never be replaced, as every update settles the reward first
+    pub fn assert_valid(&self) {
+        assert!(self.reward_denom > 0, "Reward denominator must be positive");
+        assert!(self.referral_reward_bps <= BPS_DENOMINATOR, "Referral reward cannot exceed 100%");
+        if let Some(vesting) = self.vesting {
+            assert!(vesting.cliff_epochs <= vesting.duration_epochs, "Vesting cliff cannot be after the end of the vesting");
+        }
+        if let Some(position) = self.position {
+            assert!(
+                position.min_lock_epochs > 0 && position.min_lock_epochs <= position.max_lock_epochs,
+                "Lock bounds must satisfy 0 < min_lock_epochs <= max_lock_epochs"
+            );
+            assert!(position.max_boost_bps <= BPS_DENOMINATOR, "Boost cannot exceed 100%");
+        }
+    }
+}
@@ -0,0 +1,20 @@
+use crate::*;
+
+// Config layout of the contract versions 1 and 2
+#[derive(BorshDeserialize, BorshSerialize, Clone, Copy)]
+pub struct ConfigV1 {
+    pub reward_num: u32,        // Incentive numerator
+    pub reward_denom: u64,      // Incentive denomerator
+    pub num_epoch_unlock: u64,  // Number of epoch need to wait to withdraw when unstake
+}
+
+impl From<ConfigV1> for Config {
+    fn from(config: ConfigV1) -> Self {
+        Config {
+            reward_num: config.reward_num,
+            reward_denom: config.reward_denom,
+            num_epoch_unlock: config.num_epoch_unlock,
+            ..Config::default()
+        }
+    }
+}
@@ -1,8 +1,11 @@
 use crate::*;
+use near_sdk::serde_json::json;
 
 pub const FT_TRANSFER_GAS: Gas = 10_000_000_000_000;
 pub const HARVEST_CALLBACK_GAS: Gas = 10_000_000_000_000;
 pub const WITHDRAW_CALLBACK_GAS: Gas = 10_000_000_000_000;
+pub const CLAIM_VESTED_CALLBACK_GAS: Gas = 10_000_000_000_000;
+pub const EMERGENCY_WITHDRAW_CALLBACK_GAS: Gas = 10_000_000_000_000;
 
 pub trait FungibleTokenReceiver {
     // When receive tokens from user through FT contract
@@ -13,6 +16,9 @@ pub trait FungibleTokenReceiver {
 pub trait FungibleTokenCore {
     // To transfer token when user harvest
     fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+
+    // To check the solvency of the contract
+    fn ft_balance_of(&self, account_id: AccountId) -> U128;
 }
 
 #[ext_contract(ext_self)]
@@ -22,15 +28,74 @@ pub trait ExtStakingContract {
 
     // For withdraw callback after withdraw (mostly to handle fail case and rollback)
     fn ft_withdraw_callback(&mut self, account_id: AccountId, old_account: Account);
+
+    // For claim vested callback (rollback the released amount when transfer failed)
+    fn ft_claim_vested_callback(&mut self, account_id: AccountId, amount: U128);
+
+    // For emergency withdraw callback (rollback the withdrawn part when transfer failed)
+    fn ft_emergency_withdraw_callback(&mut self, account_id: AccountId, old_account: Account);
+
+    // For solvency check callback (compare the token balance with the liabilities)
+    fn ft_solvency_callback(&mut self, liabilities: U128);
+
+    // For stake transfer callback (give back the stake unused by the receiver)
+    fn stake_resolve_transfer(&mut self, sender_id: AccountId, receiver_id: AccountId, amount: U128);
+
+    // For receipt token transfer callback (same as above, for `ft_transfer_call`)
+    fn ft_resolve_transfer(&mut self, sender_id: AccountId, receiver_id: AccountId, amount: U128);
+
+    // For close account callback (restore the account when transfer failed)
+    fn ft_close_account_callback(&mut self, account_id: AccountId, old_account: Account, vested: U128, storage_charge: StorageCharge);
+
+    // For airdrop withdraw callback (restore the airdrop balance when transfer failed)
+    fn ft_airdrop_withdraw_callback(&mut self, amount: U128);
+
+    // For staked token rescue, once the token balance is read (transfer the surplus)
+    fn ft_rescue_surplus_callback(&mut self, receiver_id: AccountId, amount: U128);
+
+    // For staked token rescue callback (the rescued amount is no longer in flight)
+    fn ft_rescue_callback(&mut self, amount: U128);
+
+    // For position transfer callback (give the token back if the receiver asks for it)
+    fn nft_resolve_transfer(
+        &mut self,
+        previous_owner_id: AccountId,
+        receiver_id: AccountId,
+        token_id: TokenId,
+        approved_account_ids: Option<std::collections::HashMap<AccountId, u64>>
+    );
 }
 
 #[near_bindgen]
 impl FungibleTokenReceiver for StakingContract {
     fn ft_on_transfer(&mut self, sender_id: AccountId, amount: U128, msg: String) -> PromiseOrValue<U128> {
         env::log(format!("User {} staking {} NEAR with message \"{}\"", sender_id, amount.0, msg).as_bytes());
-        self.internal_deposit_and_stake(sender_id, amount.0);
+        let stake_msg: StakeMsg = if msg.is_empty() {
+            StakeMsg::default()
+        } else {
+            near_sdk::serde_json::from_str(&msg).expect("Invalid staking message")
+        };
 
-        PromiseOrValue::Value(U128(0))
+        if stake_msg.action == Some(TransferAction::FundAirdrop) {
+            self.internal_fund_airdrop(&sender_id, amount.0);
+            return PromiseOrValue::Value(U128(0));
+        }
+
+        // The stake can be credited to another account
+        let account_id = stake_msg.beneficiary_id.unwrap_or_else(|| sender_id.clone());
+        if account_id != sender_id {
+            self.assert_not_denied(&sender_id);
+            env::log(format!("User {} staking on behalf of {}", sender_id, account_id).as_bytes());
+        }
+        if self.accounts.get(&account_id).is_none() {
+            self.internal_register_on_transfer(&sender_id, &account_id, stake_msg.referrer_id, amount.0);
+        } else if stake_msg.referrer_id.is_some() {
+            env::log(b"The referrer is ignored, it can only be chosen at registration");
+        }
+        let unused_amount = self.internal_deposit_and_stake(account_id, amount.0);
+
+        // The unused amount is refunded by the fungible token contract
+        PromiseOrValue::Value(U128(unused_amount))
     }
 }
 
@@ -38,17 +103,30 @@ impl FungibleTokenReceiver for StakingContract {
 impl StakingContract {
 
     // Harvest the reward to the wallet
+    // In vesting mode, the reward is moved to the vesting schedule instead
     #[payable]
-    pub fn harvest(&mut self) -> Promise {
+    pub fn harvest(&mut self) -> PromiseOrValue<U128> {
         assert_one_yocto();
         let account_id: AccountId = env::predecessor_account_id();
+        self.assert_not_locked(&account_id);
         let upgradable_account = self.accounts.get(&account_id).unwrap();
-        let account = Account::from(upgradable_account);
+        let mut account = Account::from(upgradable_account);
 
-        let new_reward = self.internal_calculate_new_reward(Some(&account));
-        let current_reward = self.pre_reward + new_reward;
+        self.internal_update_account_reward(&mut account);
+        let current_reward = account.pre_reward;
         assert!(current_reward > 0, "Your reward is zero");
 
+        // The reward is deducted before the transfer, and restored by the callback if it fails
+        account.pre_reward = 0;
+        self.internal_save_account(&account_id, account);
+
+        if let Some(vesting) = self.config.vesting {
+            self.internal_vest_reward(&account_id, current_reward, &vesting);
+            return PromiseOrValue::Value(U128(current_reward));
+        }
+        self.internal_lock_account(&account_id, PendingOperation::Harvest);
+        self.pending_transfers += current_reward;
+
         ext_ft::ft_transfer(
             account_id.clone(), 
             U128(current_reward), 
@@ -62,27 +140,30 @@ impl StakingContract {
             &env::current_account_id(), 
             0, 
             HARVEST_CALLBACK_GAS
-        ))
+        )).into()
     }
 
     #[private]
     pub fn ft_harvest_callback(&mut self, account_id: AccountId, amount: U128) -> U128 {
         assert_eq!(env::promise_results_count(), 1, "Too many result of promise");
+        self.internal_unlock_account(&account_id);
+        self.pending_transfers -= amount.0;
         match env::promise_result(0) {
             PromiseResult::NotReady => unreachable!(),          // Will not be handle
             PromiseResult::Successful(_value) => {
-                let upgradable_account = self.accounts.get(&account_id).unwrap();
-                let mut account = Account::from(upgradable_account);
-
-                account.pre_reward = 0;
-                account.last_block_balance_change = env::block_index();
-
-                self.accounts.insert(&account_id, &UpgradableAccount::from(account));
+                env::log(format!("Harvested {} for {}", amount.0, account_id).as_bytes());
                 self.total_paid_reward += amount.0;
-
                 amount
             },
-            PromiseResult::Failed => env::panic("Callback failed".as_bytes()),
+            PromiseResult::Failed => {
+                // Only the harvested amount is restored, the account may have earned referral reward since
+                env::log(format!("Harvest of {} failed for {}, the reward is restored", amount.0, account_id).as_bytes());
+                let upgradable_account = self.accounts.get(&account_id).unwrap();
+                let mut account = Account::from(upgradable_account);
+                account.pre_reward += amount.0;
+                self.internal_save_account(&account_id, account);
+                U128(0)
+            },
         }
     }
 
@@ -100,6 +181,8 @@ impl StakingContract {
         assert_one_yocto();
         let account_id = env::predecessor_account_id();
         let old_account = self.internal_withdraw(account_id.clone());
+        self.internal_lock_account(&account_id, PendingOperation::Withdraw);
+        self.pending_transfers += old_account.unstake_balance;
 
         ext_ft::ft_transfer(
             account_id.clone(), 
@@ -120,13 +203,137 @@ impl StakingContract {
     #[private]
     pub fn ft_withdraw_callback(&mut self, account_id: AccountId, old_account: Account) -> U128{
         assert_eq!(env::promise_results_count(), 1, "Too many promise results");
+        self.internal_unlock_account(&account_id);
+        self.pending_transfers -= old_account.unstake_balance;
         match env::promise_result(0) {
             PromiseResult::NotReady => unreachable!(),
             PromiseResult::Successful(_value) => {
+                self.total_withdrawn += old_account.unstake_balance;
                 U128(old_account.unstake_balance)
             },
             PromiseResult::Failed => {
-                self.accounts.insert(&account_id, &UpgradableAccount::from(old_account));
+                // Only the unstaking balance is restored, the account may have earned referral reward since
+                let mut account = Account::from(self.accounts.get(&account_id).unwrap());
+                account.unstake_balance += old_account.unstake_balance;
+                account.unstake_start_time = old_account.unstake_start_time;
+                account.unstake_available_epoch = old_account.unstake_available_epoch;
+                self.total_unstaking += old_account.unstake_balance;
+                self.internal_save_account(&account_id, account);
+                U128(0)
+            }
+        }
+    }
+
+    // Claim the released part of the vesting schedule to the wallet
+    #[payable]
+    pub fn claim_vested(&mut self) -> Promise {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        self.assert_not_locked(&account_id);
+        let mut schedule = self.internal_get_vesting_schedule(&account_id).expect("Vesting schedule not found");
+
+        schedule.release(env::epoch_height());
+        let amount = schedule.unlocked_balance;
+        assert!(amount > 0, "Nothing is vested yet");
+
+        schedule.unlocked_balance = 0;
+        self.internal_save_vesting_schedule(&account_id, &schedule);
+        self.internal_lock_account(&account_id, PendingOperation::ClaimVested);
+        self.pending_transfers += amount;
+
+        ext_ft::ft_transfer(
+            account_id.clone(), 
+            U128(amount), 
+            Some("Claim vested reward from staking".to_string()), 
+            &self.ft_contract_id, 
+            1, 
+            FT_TRANSFER_GAS
+        ).then(ext_self::ft_claim_vested_callback(
+            account_id, 
+            U128(amount), 
+            &env::current_account_id(), 
+            0, 
+            CLAIM_VESTED_CALLBACK_GAS
+        ))
+    }
+
+    #[private]
+    pub fn ft_claim_vested_callback(&mut self, account_id: AccountId, amount: U128) -> U128 {
+        assert_eq!(env::promise_results_count(), 1, "Too many promise results");
+        self.internal_unlock_account(&account_id);
+        self.pending_transfers -= amount.0;
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_value) => {
+                self.total_paid_reward += amount.0;
+                amount
+            },
+            PromiseResult::Failed => {
+                let mut schedule = self.internal_get_vesting_schedule(&account_id).unwrap();
+                schedule.unlocked_balance += amount.0;
+                self.internal_save_vesting_schedule(&account_id, &schedule);
+                U128(0)
+            }
+        }
+    }
+
+    // Recover staked and unstaking tokens while the contract is paused
+    // The pending reward is forfeited and the unlock epoch is ignored, the locked positions stay locked
+    #[payable]
+    pub fn emergency_withdraw(&mut self) -> Promise {
+        assert_one_yocto();
+        assert!(self.paused, "Emergency withdraw is only available while paused");
+        let account_id = env::predecessor_account_id();
+        let old_account = self.internal_emergency_withdraw(account_id.clone());
+        self.internal_lock_account(&account_id, PendingOperation::EmergencyWithdraw);
+        self.pending_transfers += old_account.stake_balance + old_account.unstake_balance;
+
+        ext_ft::ft_transfer(
+            account_id.clone(), 
+            U128(old_account.stake_balance + old_account.unstake_balance), 
+            Some("Emergency withdraw from staking contract".to_string()), 
+            &self.ft_contract_id, 
+            1, 
+            FT_TRANSFER_GAS
+        ).then(ext_self::ft_emergency_withdraw_callback(
+            account_id, 
+            old_account, 
+            &env::current_account_id(), 
+            0, 
+            EMERGENCY_WITHDRAW_CALLBACK_GAS
+        ))
+    }
+
+    #[private]
+    pub fn ft_emergency_withdraw_callback(&mut self, account_id: AccountId, old_account: Account) -> U128 {
+        assert_eq!(env::promise_results_count(), 1, "Too many promise results");
+        self.internal_unlock_account(&account_id);
+        self.pending_transfers -= old_account.stake_balance + old_account.unstake_balance;
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_value) => {
+                self.total_withdrawn += old_account.stake_balance + old_account.unstake_balance;
+                U128(old_account.stake_balance + old_account.unstake_balance)
+            },
+            PromiseResult::Failed => {
+                // The withdrawn balances and the forfeited reward are restored onto the current account,
+                // which may have earned referral reward since
+                self.internal_update_pool_reward();
+                let mut account = Account::from(self.accounts.get(&account_id).unwrap());
+                if old_account.stake_balance > 0 && account.stake_balance == 0 {
+                    self.num_staker += 1;
+                }
+                account.pre_reward += old_account.pre_reward;
+                account.stake_balance += old_account.stake_balance;
+                account.unstake_balance += old_account.unstake_balance;
+                account.unstake_start_time = old_account.unstake_start_time;
+                account.unstake_available_epoch = old_account.unstake_available_epoch;
+                self.total_stake += old_account.stake_balance;
+                self.total_unstaking += old_account.unstake_balance;
+                if old_account.stake_balance > 0 {
+                    emit_ft_event("ft_mint", json!({ "owner_id": account_id, "amount": U128(old_account.stake_balance) }));
+                }
+                self.internal_save_account(&account_id, account);
                 U128(0)
             }
         }
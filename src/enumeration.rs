@@ -13,7 +13,88 @@ impl StakingContract {
         )
     }
 
+    pub fn get_pending_operation(&self, account_id: AccountId) -> Option<PendingOperation> {
+        self.pending_operations.get(&account_id)
+    }
+
     pub fn get_pool_info(&self) -> PoolJson {
-        PoolJson::from(&self)
+        PoolJson::from(self)
+    }
+
+    pub fn get_reward_rate_per_block(&self) -> RewardRateJson {
+        let (reward_per_share, _) = self.internal_reward_per_share_until(self.internal_reward_block());
+        let (next_reward_per_share, _) = self.internal_reward_per_share_until(self.internal_projected_reward_block(1));
+        RewardRateJson {
+            reward_num: self.config.reward_num,
+            reward_denom: U64(self.config.reward_denom),
+            pool_reward_per_block: U128(calculate_reward(self.total_reward_weight, next_reward_per_share - reward_per_share, 0).0),
+            is_paused: self.paused,
+        }
+    }
+
+    // Yearly reward of a stake in basis points, zero while paused
+    pub fn get_current_apr(&self) -> u32 {
+        let account = self.internal_new_account();
+        let reward = self.internal_estimate_reward(account, BPS_DENOMINATOR as Balance, BLOCKS_PER_YEAR);
+        reward.min(u32::MAX as Balance) as u32
+    }
+
+    // Reward over the next blocks (or epochs), either of an account, of a new stake of `amount`, or of both
+    // The reward already pending is not included
+    pub fn estimate_reward(&self, account_id: Option<AccountId>, amount: Option<U128>, num_blocks: Option<u64>, num_epochs: Option<u64>) -> U128 {
+        assert!(account_id.is_some() || amount.is_some(), "Either the account or the amount is required");
+        let num_blocks = match (num_blocks, num_epochs) {
+            (Some(num_blocks), None) => num_blocks,
+            (None, Some(num_epochs)) => num_epochs * BLOCKS_PER_EPOCH,
+            _ => env::panic("Either the number of blocks or of epochs is required".as_bytes()),
+        };
+        let account = match account_id {
+            Some(account_id) => Account::from(self.accounts.get(&account_id).expect("Account not found")),
+            None => self.internal_new_account(),
+        };
+        U128(self.internal_estimate_reward(account, amount.map_or(0, |amount| amount.0), num_blocks))
+    }
+
+    pub fn get_vesting_info(&self, account_id: AccountId) -> Option<VestingJson> {
+        self.internal_get_vesting_schedule(&account_id).map(|schedule| VestingJson::from(account_id, schedule))
+    }
+
+    pub fn get_referral_info(&self, account_id: AccountId) -> ReferralJson {
+        let num_referees = self.referees.get(&account_id).map_or(0, |referees| referees.len());
+        let referral_earnings = self.referral_earnings.get(&account_id).unwrap_or(0);
+        ReferralJson {
+            account_id,
+            num_referees,
+            referral_earnings: U128(referral_earnings),
+        }
+    }
+
+    pub fn get_referees(&self, account_id: AccountId, from_index: Option<u64>, limit: Option<u64>) -> Vec<AccountId> {
+        let referees = match self.referees.get(&account_id) {
+            Some(referees) => referees,
+            None => return vec![],
+        };
+        let referees = referees.as_vector();
+        page_range(referees.len(), from_index, limit)
+            .map(|index| referees.get(index).unwrap())
+            .collect()
+    }
+
+    pub fn is_allowlist_enabled(&self) -> bool {
+        self.allowlist_enabled
+    }
+
+    pub fn get_allowlist(&self, from_index: Option<u64>, limit: Option<u64>) -> Vec<AccountId> {
+        let allowlist = self.allowlist.as_vector();
+        page_range(allowlist.len(), from_index, limit)
+            .map(|index| allowlist.get(index).unwrap())
+            .collect()
+    }
+
+    pub fn get_denylist(&self, from_index: Option<u64>, limit: Option<u64>) -> Vec<AccountId> {
+        let denylist = self.denylist.as_vector();
+        page_range(denylist.len(), from_index, limit)
+            .map(|index| denylist.get(index).unwrap())
+            .collect()
     }
 }
\ No newline at end of file
@@ -0,0 +1,32 @@
+use crate::*;
+use near_sdk::serde_json::{json, Value};
+
+pub const EVENT_STANDARD: &str = "staking-contract";
+pub const EVENT_VERSION: &str = "1.0.0";
+pub const FT_EVENT_STANDARD: &str = "nep141";
+pub const NFT_EVENT_STANDARD: &str = "nep171";
+
+// Log events in the NEP-297 format, so that indexers can parse them
+pub fn emit_event(event: &str, data: Value) {
+    log_event(EVENT_STANDARD, event, data);
+}
+
+// Mint, burn and transfer of the receipt token (see `receipt_token.rs`)
+pub fn emit_ft_event(event: &str, data: Value) {
+    log_event(FT_EVENT_STANDARD, event, data);
+}
+
+// Mint, burn and transfer of the locked positions (see `nft.rs`)
+pub fn emit_nft_event(event: &str, data: Value) {
+    log_event(NFT_EVENT_STANDARD, event, data);
+}
+
+fn log_event(standard: &str, event: &str, data: Value) {
+    let event = json!({
+        "standard": standard,
+        "version": EVENT_VERSION,
+        "event": event,
+        "data": [data],
+    });
+    env::log(format!("EVENT_JSON:{}", event).as_bytes());
+}
@@ -1,34 +1,208 @@
 use crate::*;
+use near_sdk::serde_json::json;
 
 // Using pub(crate) to only callable from inside the program instead of outside by command
 
 #[near_bindgen]
 impl StakingContract {
+    pub(crate) fn assert_owner(&self) {
+        assert_eq!(env::predecessor_account_id(), self.owner_id, "Only the owner can call this function");
+    }
+
+    pub(crate) fn assert_role(&self, role: Role) {
+        assert!(
+            self.has_role(role, env::predecessor_account_id()),
+            "Only the owner or a member of the {:?} role can call this function",
+            role
+        );
+    }
+
+    pub(crate) fn internal_role_members(&self, role: Role) -> UnorderedSet<AccountId> {
+        self.roles.get(&role).unwrap_or_else(|| {
+            UnorderedSet::new(StorageKey::RoleMembersKey { role })
+        })
+    }
+
+    // Every account write goes through here, so that legacy entries are counted when they are rewritten,
+    // new accounts are indexed, the pool total follows the weight of the account,
+    // and the sums of a running invariant scan follow the accounts it already scanned
+    pub(crate) fn internal_save_account(&mut self, account_id: &AccountId, mut account: Account) {
+        // The reward until now is earned with the previous weight
+        let reward_weight = account.weight();
+        if reward_weight != account.reward_weight {
+            self.internal_update_account_reward(&mut account);
+            account.reward_weight = reward_weight;
+        }
+
+        let is_scanned = self.internal_is_scanned(account_id);
+        let new_totals = if is_scanned { Some(self.internal_account_totals(account_id, &account)) } else { None };
+        let new_weight = account.reward_weight;
+        let old_account = self.accounts.insert(account_id, &UpgradableAccount::from(account));
+        match old_account {
+            Some(old_account) => {
+                if old_account.is_legacy() {
+                    self.internal_count_legacy_account(&old_account);
+                    self.internal_index_account(account_id);
+                }
+                let old_account = Account::from(old_account);
+                self.total_reward_weight = self.total_reward_weight - old_account.reward_weight + new_weight;
+                if let Some(new_totals) = new_totals {
+                    let old_totals = self.internal_account_totals(account_id, &old_account);
+                    let scan = self.invariant_scan.as_mut().unwrap();
+                    scan.totals.sub(&old_totals);
+                    scan.totals.add(&new_totals);
+                }
+            },
+            None => {
+                self.internal_index_account(account_id);
+                self.total_reward_weight += new_weight;
+            },
+        }
+    }
+
+    // Counterpart of `internal_save_account`, for closed accounts
+    // The vesting schedule is counted with the account, so it must be removed after it
+    // Return the number of bytes freed by the account entry itself
+    pub(crate) fn internal_remove_account(&mut self, account_id: &AccountId) -> StorageUsage {
+        let storage_usage_before = env::storage_usage();
+        let old_account = match self.accounts.remove(account_id) {
+            Some(old_account) => old_account,
+            None => return 0,
+        };
+        let entry_storage_usage = storage_usage_before - env::storage_usage();
+        if old_account.is_legacy() {
+            self.internal_count_legacy_account(&old_account);
+        }
+        let old_account = Account::from(old_account);
+        self.total_reward_weight -= old_account.reward_weight;
+
+        let index = match self.account_indices.get(account_id) {
+            Some(index) => index,
+            None => return entry_storage_usage,
+        };
+        let old_totals = if self.internal_is_scanned(account_id) { Some(self.internal_account_totals(account_id, &old_account)) } else { None };
+        let last_index = self.account_ids.len() - 1;
+        self.account_indices.remove(account_id);
+        self.account_ids.swap_remove(index);
+        let moved_account_id = self.account_ids.get(index);
+        if let Some(moved_account_id) = moved_account_id.as_ref() {
+            self.account_indices.insert(moved_account_id, &index);
+        }
+
+        if let Some(old_totals) = old_totals {
+            // The last account takes the place of the removed one, it is counted if it was not scanned yet
+            let moved_totals = match moved_account_id {
+                Some(moved_account_id) if last_index >= self.invariant_scan.as_ref().unwrap().next_index => {
+                    let moved_account = Account::from(self.accounts.get(&moved_account_id).unwrap());
+                    Some(self.internal_account_totals(&moved_account_id, &moved_account))
+                },
+                _ => None,
+            };
+            let scan = self.invariant_scan.as_mut().unwrap();
+            scan.totals.sub(&old_totals);
+            if let Some(moved_totals) = moved_totals {
+                scan.totals.add(&moved_totals);
+            }
+            scan.next_index = scan.totals.num_scanned;
+        }
+        entry_storage_usage
+    }
+
+    pub(crate) fn internal_index_account(&mut self, account_id: &AccountId) {
+        if self.account_indices.get(account_id).is_none() {
+            self.account_indices.insert(account_id, &self.account_ids.len());
+            self.account_ids.push(account_id);
+        }
+    }
+
+    // Whether the running invariant scan already counted the account
+    pub(crate) fn internal_is_scanned(&self, account_id: &AccountId) -> bool {
+        match (self.invariant_scan.as_ref(), self.account_indices.get(account_id)) {
+            (Some(scan), Some(index)) => index < scan.next_index,
+            _ => false,
+        }
+    }
+
+    // A legacy entry leaves the legacy totals, seeded by the migration, and its unstaking balance,
+    // which was not tracked before version 3, joins the global total
+    pub(crate) fn internal_count_legacy_account(&mut self, old_account: &UpgradableAccount) {
+        let (stake_balance, unstake_balance) = match old_account {
+            UpgradableAccount::Version1(account) => (account.stake_balance, account.unstake_balance),
+            UpgradableAccount::Version2(account) => (account.stake_balance, account.unstake_balance),
+            UpgradableAccount::Current(_) => return,
+        };
+        if stake_balance > 0 {
+            self.num_legacy_accounts -= 1;
+            self.legacy_stake -= stake_balance;
+        }
+        self.total_unstaking += unstake_balance;
+    }
+
+    // In allowlist mode, only listed accounts can register and stake
+    pub(crate) fn assert_allowed(&self, account_id: &AccountId) {
+        if self.allowlist_enabled {
+            assert!(self.allowlist.contains(account_id), "Account is not in the allowlist");
+        }
+    }
+
+    pub(crate) fn assert_not_denied(&self, account_id: &AccountId) {
+        assert!(!self.denylist.contains(account_id), "Account is in the denylist");
+    }
+
+    // Same checks as `internal_stake_deposit`, for an account which is not registered yet
+    pub(crate) fn assert_deposit_accepted(&self, account_id: &AccountId, amount: Balance) {
+        assert!(!self.is_paused(), "Contract is paused");
+        self.assert_allowed(account_id);
+        self.assert_not_denied(account_id);
+        assert!(
+            self.internal_stake_capacity(&self.internal_new_account(), amount) > 0,
+            "Deposit of {} is refused because of the stake limits",
+            amount
+        );
+    }
+
+    // No operation can start on an account while a transfer for it is in flight
+    pub(crate) fn assert_not_locked(&self, account_id: &AccountId) {
+        if let Some(operation) = self.pending_operations.get(account_id) {
+            env::panic(format!("Account has a pending {:?} operation", operation).as_bytes());
+        }
+    }
+
+    // Set before the promise, and cleared by its callback whatever the result
+    pub(crate) fn internal_lock_account(&mut self, account_id: &AccountId, operation: PendingOperation) {
+        self.assert_not_locked(account_id);
+        self.pending_operations.insert(account_id, &operation);
+    }
+
+    pub(crate) fn internal_unlock_account(&mut self, account_id: &AccountId) {
+        self.pending_operations.remove(account_id);
+    }
+
     pub(crate) fn internal_unstake(&mut self, account_id: AccountId, amount: Balance) {
+        self.assert_not_locked(&account_id);
         let upgradable_account = self.accounts.get(&account_id).unwrap();
         let mut account = Account::from(upgradable_account);
         assert!(amount <= account.stake_balance, "Cannot unstake more than the staking amount");
+        assert!(amount <= account.stake_balance - account.locked_balance, "Cannot unstake the stake of locked positions");
 
         // Update account reward
-        let new_reward = self.internal_calculate_new_reward(Some(&account));
-        account.pre_reward += new_reward;
-        account.last_block_balance_change = env::block_index();
+        self.internal_update_account_reward(&mut account);
     
         // Update account unstake
         account.stake_balance -= amount;
         account.unstake_balance += amount;
+        self.total_unstaking += amount;
         account.unstake_start_time = env::block_timestamp();
         account.unstake_available_epoch = env::epoch_height() + self.config.num_epoch_unlock;
     
         if account.stake_balance==0 {
             self.num_staker -= 1;
         }    
-        self.accounts.insert(&account_id, &UpgradableAccount::from(account));
+        self.internal_save_account(&account_id, account);
 
-        let new_contract_reward = self.internal_calculate_new_reward(None);
-        self.pre_reward += new_contract_reward;
+        self.internal_update_pool_reward();
         self.total_stake -= amount;
-        self.last_block_balance_change = env::block_index();
+        emit_ft_event("ft_burn", json!({ "owner_id": account_id, "amount": U128(amount) }));
     }
 
     pub(crate) fn internal_withdraw(&mut self, account_id: AccountId) -> Account {
@@ -46,88 +220,652 @@ impl StakingContract {
             unstake_balance: 0,               
             unstake_start_time: 0,          
             unstake_available_epoch: 0,   
+            referrer_id: account.referrer_id.clone(),
+            reward_remainder: account.reward_remainder,
+            locked_balance: account.locked_balance,
+            boost_balance: account.boost_balance,
+            reward_per_share_paid: account.reward_per_share_paid,
+            reward_weight: account.reward_weight,
         };
-        self.accounts.insert(&account_id, &UpgradableAccount::from(new_account));
+        self.internal_save_account(&account_id, new_account);
+        self.total_unstaking -= account.unstake_balance;
 
         account
     }
 
-    pub(crate) fn internal_deposit_and_stake(&mut self, sender_id: AccountId, amount: Balance) {
+    // Return the part of the deposit which is not staked because of the stake caps
+    pub(crate) fn internal_deposit_and_stake(&mut self, sender_id: AccountId, amount: Balance) -> Balance {
+        assert_eq!(self.ft_contract_id, env::predecessor_account_id(), "Only accept the correct fungible token");
+        self.internal_stake_deposit(sender_id, amount)
+    }
+
+    // Accounting of a stake whose tokens are already held by the contract, a deposit or an airdrop credit
+    #[allow(unused_parens, clippy::bool_assert_comparison)]
+    pub(crate) fn internal_stake_deposit(&mut self, sender_id: AccountId, amount: Balance) -> Balance {
         let upgradable_account = self.accounts.get(&sender_id);
         assert!(upgradable_account.is_some(), "Account not found, please registry first");
         assert_eq!(self.is_paused(), false, "Contract is paused");
-        assert_eq!(self.ft_contract_id, env::predecessor_account_id(), "Only accept the correct fungible token");
+        self.assert_allowed(&sender_id);
+        self.assert_not_denied(&sender_id);
+        self.assert_not_locked(&sender_id);
 
         // Update account
         let mut account = Account::from(upgradable_account.unwrap());
         let is_new_staker = (account.stake_balance==0);
-        let new_reward = self.internal_calculate_new_reward(Some(&account));
 
-        account.pre_reward += new_reward;                           // Update pre_reward to become the new phase of staking
+        let deposit = amount;
+        let amount = self.internal_stake_capacity(&account, deposit);
+        if amount == 0 {
+            env::log(format!("Deposit of {} is refunded because of the stake limits", deposit).as_bytes());
+            return deposit;
+        }
+
+        self.internal_update_account_reward(&mut account);          // Update pre_reward to become the new phase of staking
         account.stake_balance += amount;                            // Staking the deposit amount
-        account.last_block_balance_change = env::block_index();     // Update current block
-        self.accounts.insert(&sender_id, &UpgradableAccount::from(account));
+        self.internal_save_account(&sender_id, account);
 
         // Update pool
-        let new_global_reward = self.internal_calculate_new_reward(None);
-        self.pre_reward += new_global_reward;
+        self.internal_update_pool_reward();
         self.total_stake += amount;
-        self.last_block_balance_change = env::block_index();
         
         if is_new_staker {
             self.num_staker += 1;
         }
+        emit_ft_event("ft_mint", json!({ "owner_id": sender_id, "amount": U128(amount) }));
+
+        deposit - amount
     }
 
-    pub(crate) fn internal_create_account(&mut self, account_id: AccountId) {
-        let account = Account {
+    // Checks of a stake transfer requested by the sender, see `transfer_stake`
+    pub(crate) fn internal_checked_transfer_stake(&mut self, sender_id: &AccountId, receiver_id: &AccountId, amount: Balance, memo: Option<String>) {
+        assert!(!self.is_paused(), "Contract is paused");
+        assert_ne!(sender_id, receiver_id, "Cannot transfer stake to yourself");
+        assert!(amount > 0, "Amount must be positive");
+        assert!(self.accounts.get(receiver_id).is_some(), "Receiver not found, please registry first");
+        self.assert_not_locked(sender_id);
+        self.assert_not_locked(receiver_id);
+        self.assert_allowed(receiver_id);
+        self.assert_not_denied(receiver_id);
+
+        let sender = Account::from(self.accounts.get(sender_id).unwrap());
+        assert!(amount <= sender.stake_balance - sender.locked_balance, "Cannot transfer the stake of locked positions, transfer their tokens instead");
+        self.assert_receiver_stake_limit(receiver_id, amount);
+
+        self.internal_transfer_stake(sender_id, receiver_id, amount, memo);
+    }
+
+    pub(crate) fn assert_receiver_stake_limit(&self, receiver_id: &AccountId, amount: Balance) {
+        let receiver = Account::from(self.accounts.get(receiver_id).unwrap());
+        if let Some(max_stake_per_account) = self.config.max_stake_per_account {
+            assert!(receiver.stake_balance + amount <= max_stake_per_account.0, "Receiver would exceed the stake limit per account");
+        }
+    }
+
+    // Checks of a position transfer requested by its owner, see `nft_transfer`
+    pub(crate) fn internal_checked_transfer_position(&mut self, sender_id: &AccountId, receiver_id: &AccountId, token_id: &TokenId, memo: Option<String>) {
+        assert!(!self.is_paused(), "Contract is paused");
+        assert_ne!(sender_id, receiver_id, "Cannot transfer a position to yourself");
+        let position = self.positions.get(token_id).expect("Position not found");
+        assert_eq!(&position.owner_id, sender_id, "Only the owner of the position can transfer it");
+        assert!(self.accounts.get(receiver_id).is_some(), "Receiver not found, please registry first");
+        self.assert_not_locked(sender_id);
+        self.assert_not_locked(receiver_id);
+        self.assert_allowed(receiver_id);
+        self.assert_not_denied(receiver_id);
+        self.assert_receiver_stake_limit(receiver_id, position.amount);
+
+        self.internal_transfer_position(sender_id, receiver_id, token_id, memo);
+    }
+
+    // The stake of the position moves with its token, the locked and boost balances as well
+    pub(crate) fn internal_transfer_position(&mut self, sender_id: &AccountId, receiver_id: &AccountId, token_id: &TokenId, memo: Option<String>) {
+        let mut position = self.positions.get(token_id).unwrap();
+        self.internal_transfer_stake(sender_id, receiver_id, position.amount, memo.clone());
+
+        // Both rewards are settled by the stake transfer, in this block
+        let mut sender = Account::from(self.accounts.get(sender_id).unwrap());
+        sender.locked_balance -= position.amount;
+        sender.boost_balance -= position.boost_balance;
+        self.internal_save_account(sender_id, sender);
+        let mut receiver = Account::from(self.accounts.get(receiver_id).unwrap());
+        receiver.locked_balance += position.amount;
+        receiver.boost_balance += position.boost_balance;
+        self.internal_save_account(receiver_id, receiver);
+
+        self.internal_remove_position_from_owner(sender_id, token_id);
+        self.internal_add_position_to_owner(receiver_id, token_id);
+        position.owner_id = receiver_id.clone();
+        self.positions.insert(token_id, &position);
+        emit_nft_event("nft_transfer", json!({
+            "old_owner_id": sender_id,
+            "new_owner_id": receiver_id,
+            "token_ids": [token_id],
+            "memo": memo,
+        }));
+    }
+
+    pub(crate) fn internal_add_position_to_owner(&mut self, account_id: &AccountId, token_id: &TokenId) {
+        let mut token_ids = self.positions_per_owner.get(account_id).unwrap_or_else(|| {
+            UnorderedSet::new(StorageKey::PositionsPerAccountKey {
+                account_hash: env::sha256(account_id.as_bytes()),
+            })
+        });
+        token_ids.insert(token_id);
+        self.positions_per_owner.insert(account_id, &token_ids);
+    }
+
+    pub(crate) fn internal_remove_position_from_owner(&mut self, account_id: &AccountId, token_id: &TokenId) {
+        let mut token_ids = self.positions_per_owner.get(account_id).unwrap();
+        token_ids.remove(token_id);
+        if token_ids.is_empty() {
+            self.positions_per_owner.remove(account_id);
+        } else {
+            self.positions_per_owner.insert(account_id, &token_ids);
+        }
+    }
+
+    // Only the records, the balances of the owner are updated by the caller
+    pub(crate) fn internal_remove_position(&mut self, token_id: &TokenId, position: &Position) {
+        self.positions.remove(token_id);
+        self.internal_remove_position_from_owner(&position.owner_id, token_id);
+    }
+
+    // Both rewards are settled at the old balances, the total stake does not change
+    // This is a transfer of the receipt token as well
+    pub(crate) fn internal_transfer_stake(&mut self, sender_id: &AccountId, receiver_id: &AccountId, amount: Balance, memo: Option<String>) {
+        // Settling may pay a referral reward to the other account, so each one is saved before the next is read
+        for account_id in [sender_id, receiver_id] {
+            let mut account = Account::from(self.accounts.get(account_id).unwrap());
+            self.internal_update_account_reward(&mut account);
+            self.internal_save_account(account_id, account);
+        }
+
+        let mut sender = Account::from(self.accounts.get(sender_id).unwrap());
+        assert!(amount <= sender.stake_balance, "Cannot transfer more than the staking amount");
+        sender.stake_balance -= amount;
+        if sender.stake_balance == 0 {
+            self.num_staker -= 1;
+        }
+        self.internal_save_account(sender_id, sender);
+
+        let mut receiver = Account::from(self.accounts.get(receiver_id).unwrap());
+        if receiver.stake_balance == 0 {
+            self.num_staker += 1;
+        }
+        receiver.stake_balance += amount;
+        self.internal_save_account(receiver_id, receiver);
+        emit_ft_event("ft_transfer", json!({
+            "old_owner_id": sender_id,
+            "new_owner_id": receiver_id,
+            "amount": U128(amount),
+            "memo": memo,
+        }));
+    }
+
+    // Give back the stake unused by the receiver of `transfer_stake_call` or `ft_transfer_call`
+    // Return the amount of stake kept by the receiver
+    pub(crate) fn internal_resolve_stake_transfer(&mut self, sender_id: &AccountId, receiver_id: &AccountId, amount: U128) -> U128 {
+        assert_eq!(env::promise_results_count(), 1, "Too many promise results");
+        self.internal_unlock_account(sender_id);
+        let unused_amount = match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(value) => match near_sdk::serde_json::from_slice::<U128>(&value) {
+                Ok(unused_amount) => unused_amount.0.min(amount.0),
+                Err(_) => amount.0,
+            },
+            PromiseResult::Failed => amount.0,
+        };
+
+        // A receiver with an operation in flight is not touched, its callback could restore an older state
+        let mut refund_amount = 0;
+        if unused_amount > 0 && self.pending_operations.get(receiver_id).is_none() {
+            let receiver = Account::from(self.accounts.get(receiver_id).unwrap());
+            refund_amount = unused_amount.min(receiver.stake_balance - receiver.locked_balance);
+            if refund_amount > 0 {
+                self.internal_transfer_stake(receiver_id, sender_id, refund_amount, Some("refund".to_string()));
+            }
+        }
+        U128(amount.0 - refund_amount)
+    }
+
+    // The part of `amount` that the account can stake without exceeding the caps of the config
+    pub(crate) fn internal_stake_capacity(&self, account: &Account, amount: Balance) -> Balance {
+        let mut capacity = amount;
+        if let Some(max_stake_per_account) = self.config.max_stake_per_account {
+            capacity = capacity.min(max_stake_per_account.0.saturating_sub(account.stake_balance));
+        }
+        if let Some(max_total_stake) = self.config.max_total_stake {
+            capacity = capacity.min(max_total_stake.0.saturating_sub(self.total_stake));
+        }
+        if let Some(min_stake_amount) = self.config.min_stake_amount {
+            if capacity < min_stake_amount.0 {
+                capacity = 0;
+            }
+        }
+        capacity
+    }
+
+    // A closing account cannot be registered again before its callback, which may restore it
+    pub(crate) fn internal_create_account(&mut self, account_id: AccountId, referrer_id: Option<AccountId>) {
+        self.assert_not_locked(&account_id);
+        let mut account = self.internal_new_account();
+        if let Some(referrer_id) = referrer_id {
+            self.internal_set_referrer(&account_id, &mut account, referrer_id);
+        }
+        self.internal_save_account(&account_id, account);
+    }
+
+    // Bytes used by the registration of an account with the longest ids and a referrer, which is the largest one
+    // Both the entry of a storage pool registration and a charge paid by an account are counted, as an upper bound
+    // The ids are not valid account ids, so they cannot be registered by anyone, and the entries are removed at once
+    pub(crate) fn internal_measure_account_storage_usage(&mut self) -> StorageUsage {
+        let referrer_id = "R".repeat(64);
+        let account_id = "A".repeat(64);
+        self.internal_create_account(referrer_id.clone(), None);
+        let storage_usage_before = env::storage_usage();
+        self.internal_create_account(account_id.clone(), Some(referrer_id.clone()));
+        self.storage_pool_usage.insert(&account_id, &0);
+        self.internal_record_storage_charge(&account_id, StoragePayer::Account(referrer_id.clone()), storage_usage_before);
+        let storage_usage = env::storage_usage() - storage_usage_before;
+
+        self.storage_charges.remove(&account_id);
+        self.storage_pool_usage.remove(&account_id);
+        self.internal_remove_account(&account_id);
+        self.internal_remove_account(&referrer_id);
+        let mut referees = self.referees.get(&referrer_id).unwrap();
+        referees.clear();
+        self.referees.remove(&referrer_id);
+        storage_usage
+    }
+
+    // Return the number of bytes used by the new account, paid by the account itself
+    pub(crate) fn internal_register_account(&mut self, account_id: AccountId, referrer_id: Option<AccountId>) -> StorageUsage {
+        let storage_usage_before = env::storage_usage();
+        self.internal_create_account(account_id.clone(), referrer_id);
+        self.internal_record_storage_charge(&account_id, StoragePayer::Account(account_id.clone()), storage_usage_before);
+        env::storage_usage() - storage_usage_before
+    }
+
+    // Record the cost of a registration since `storage_usage_before`, including the record itself
+    pub(crate) fn internal_record_storage_charge(&mut self, account_id: &AccountId, payer: StoragePayer, storage_usage_before: StorageUsage) -> Balance {
+        let mut storage_charge = StorageCharge { payer, amount: U128(0) };
+        self.storage_charges.insert(account_id, &storage_charge);
+        storage_charge.amount = U128(env::storage_byte_cost() * Balance::from(env::storage_usage() - storage_usage_before));
+        self.storage_charges.insert(account_id, &storage_charge);
+        storage_charge.amount.0
+    }
+
+    // Unknown accounts staking through `ft_on_transfer` are registered with the storage prepaid by the sender
+    // (for a beneficiary), or else with the storage pool in auto registration mode
+    // The storage pool only pays for senders registering themselves, so that its limit per account holds per sender
+    // Only a sender registering itself can choose its referrer
+    // No storage is spent for a deposit which would be refused anyway
+    pub(crate) fn internal_register_on_transfer(&mut self, sender_id: &AccountId, account_id: &AccountId, referrer_id: Option<AccountId>, amount: Balance) {
+        self.assert_deposit_accepted(account_id, amount);
+        let referrer_id = referrer_id.filter(|_| account_id == sender_id);
+        match self.config.auto_register {
+            Some(auto_register) if account_id == sender_id => {
+                self.internal_auto_register(account_id, &auto_register, referrer_id);
+            },
+            _ if account_id != sender_id => {
+                self.internal_register_beneficiary(sender_id, account_id);
+            },
+            _ => {},    // Rejected by `internal_deposit_and_stake`
+        }
+    }
+
+    // The storage of the account (and of its usage entry) is paid by the storage pool, within the limit per account
+    // Both entries are cleared when the account is closed, and the storage goes back to the pool
+    pub(crate) fn internal_auto_register(&mut self, account_id: &AccountId, auto_register: &AutoRegisterConfig, referrer_id: Option<AccountId>) {
+        self.assert_allowed(account_id);
+        let storage_usage_before = env::storage_usage();
+        self.internal_create_account(account_id.clone(), referrer_id);
+        let used = self.storage_pool_usage.get(account_id).unwrap_or(0);
+        self.storage_pool_usage.insert(account_id, &used);
+        let cost = self.internal_record_storage_charge(account_id, StoragePayer::StoragePool, storage_usage_before);
+
+        assert!(used + cost <= auto_register.max_storage_per_account.0, "Storage pool limit of the account is reached");
+        assert!(cost <= self.storage_pool, "Storage pool cannot cover the registration");
+        self.storage_pool -= cost;
+        self.storage_pool_usage.insert(account_id, &(used + cost));
+        env::log(format!("Account {} registered with {} from the storage pool", account_id, cost).as_bytes());
+    }
+
+    // Register the beneficiary of a stake, paid from the storage prepaid by the sender, which gets it back on close
+    pub(crate) fn internal_register_beneficiary(&mut self, sender_id: &AccountId, beneficiary_id: &AccountId) {
+        self.assert_allowed(beneficiary_id);
+        let storage_usage_before = env::storage_usage();
+        self.internal_create_account(beneficiary_id.clone(), None);
+        let cost = self.internal_record_storage_charge(beneficiary_id, StoragePayer::Account(sender_id.clone()), storage_usage_before);
+
+        let prepaid = self.prepaid_storage.get(sender_id).unwrap_or(0);
+        assert!(prepaid >= cost, "Beneficiary not found, and the prepaid storage of the sender cannot register it");
+        self.prepaid_storage.insert(sender_id, &(prepaid - cost));
+        self.total_prepaid_storage -= cost;
+    }
+
+    // Tokens transferred by the owner with the `fund_airdrop` action
+    pub(crate) fn internal_fund_airdrop(&mut self, sender_id: &AccountId, amount: Balance) {
+        assert_eq!(self.ft_contract_id, env::predecessor_account_id(), "Only accept the correct fungible token");
+        assert!(self.has_role(Role::TreasuryManager, sender_id.clone()), "Only the owner or a treasury manager can fund the airdrop");
+        self.airdrop_balance += amount;
+        emit_event("airdrop_funded", json!({
+            "amount": U128(amount),
+            "airdrop_balance": U128(self.airdrop_balance),
+        }));
+    }
+
+    // The checks of `internal_stake_deposit` which would make the whole batch fail
+    pub(crate) fn internal_can_receive_airdrop(&self, account_id: &AccountId) -> bool {
+        self.accounts.get(account_id).is_some()
+            && (!self.allowlist_enabled || self.allowlist.contains(account_id))
+            && !self.denylist.contains(account_id)
+            && self.pending_operations.get(account_id).is_none()
+    }
+
+    // Empty account, starting to earn reward from the current block
+    pub(crate) fn internal_new_account(&self) -> Account {
+        Account {
             stake_balance: 0,
             pre_reward: 0,
-            last_block_balance_change: env::block_index(),
+            last_block_balance_change: self.internal_reward_block(),
             unstake_balance: 0,
             unstake_start_time: 0,
             unstake_available_epoch: 0,
             membership: Membership::Basic,
+            referrer_id: None,
+            reward_remainder: 0,
+            locked_balance: 0,
+            boost_balance: 0,
+            reward_per_share_paid: self.internal_reward_per_share_until(self.internal_reward_block()).0,
+            reward_weight: 0,
+        }
+    }
+
+    pub(crate) fn internal_set_referrer(&mut self, account_id: &AccountId, account: &mut Account, referrer_id: AccountId) {
+        assert_ne!(account_id, &referrer_id, "Cannot refer yourself");
+        assert!(self.accounts.get(&referrer_id).is_some(), "Referrer not found");
+
+        let mut referees = self.referees.get(&referrer_id).unwrap_or_else(|| {
+            UnorderedSet::new(StorageKey::RefereesPerAccountKey {
+                account_hash: env::sha256(referrer_id.as_bytes()),
+            })
+        });
+        referees.insert(account_id);
+        self.referees.insert(&referrer_id, &referees);
+        account.referrer_id = Some(referrer_id);
+    }
+
+    // The referees of a closing account pay its referral reward until now, and then have no referrer anymore
+    pub(crate) fn internal_detach_referees(&mut self, referrer_id: &AccountId) {
+        let mut referees = match self.referees.get(referrer_id) {
+            Some(referees) => referees,
+            None => return,
         };
-        self.accounts.insert(
-            &account_id, 
-            &UpgradableAccount::from(account)
-        );
+        for referee_id in referees.to_vec() {
+            // A referee closing as well is detached if its transfer fails
+            if let Some(upgradable_account) = self.accounts.get(&referee_id) {
+                let mut referee = Account::from(upgradable_account);
+                self.internal_update_account_reward(&mut referee);
+                referee.referrer_id = None;
+                self.internal_save_account(&referee_id, referee);
+            }
+        }
+        referees.clear();
+        self.referees.remove(referrer_id);
+    }
+
+    // Settle the reward of an account until the current block
+    // The referrer earns its share on top, paid from the reward reserve
+    pub(crate) fn internal_update_account_reward(&mut self, account: &mut Account) {
+        // The account reward is computed from the reward per share, which is settled first
+        self.internal_update_pool_reward();
+        let (new_reward, reward_remainder) = self.internal_calculate_new_reward_and_remainder(Some(account));
+        account.pre_reward = account.pre_reward.saturating_add(new_reward);
+        account.reward_remainder = reward_remainder;
+        account.reward_per_share_paid = self.reward_per_share;
+        account.last_block_balance_change = self.internal_reward_block();
+
+        if let Some(referrer_id) = account.referrer_id.as_ref() {
+            let referral_reward = mul_div(new_reward, self.config.referral_reward_bps as Balance, BPS_DENOMINATOR as Balance);
+            if referral_reward > 0 {
+                self.internal_pay_referral_reward(referrer_id, referral_reward);
+            }
+        }
+    }
+
+    // Settle the reward of the pool until the current block, before the total stake changes
+    pub(crate) fn internal_update_pool_reward(&mut self) {
+        let reward_block = self.internal_reward_block();
+        let (new_reward, reward_remainder) = self.internal_calculate_new_reward_and_remainder(None);
+        let (reward_per_share, reward_per_share_remainder) = self.internal_reward_per_share_until(reward_block);
+        self.pre_reward = self.pre_reward.saturating_add(new_reward);
+        self.reward_remainder = reward_remainder;
+        self.reward_per_share = reward_per_share;
+        self.reward_per_share_remainder = reward_per_share_remainder;
+        self.last_block_balance_change = reward_block;
+    }
+
+    // Nothing is paid to a referrer which closed its account
+    pub(crate) fn internal_pay_referral_reward(&mut self, referrer_id: &AccountId, amount: Balance) {
+        let upgradable_account = match self.accounts.get(referrer_id) {
+            Some(upgradable_account) => upgradable_account,
+            None => return,
+        };
+        let mut referrer = Account::from(upgradable_account);
+        referrer.pre_reward += amount;
+        self.internal_save_account(referrer_id, referrer);
+
+        let earnings = self.referral_earnings.get(referrer_id).unwrap_or(0);
+        self.referral_earnings.insert(referrer_id, &(earnings + amount));
+        self.pre_reward += amount;
+    }
+
+    pub(crate) fn internal_get_vesting_schedule(&self, account_id: &AccountId) -> Option<VestingSchedule> {
+        self.vesting_schedules.get(account_id)
+    }
+
+    pub(crate) fn internal_save_vesting_schedule(&mut self, account_id: &AccountId, schedule: &VestingSchedule) {
+        self.internal_adjust_scanned_vesting(account_id, schedule.locked_balance() + schedule.unlocked_balance);
+        self.vesting_schedules.insert(account_id, schedule);
+    }
+
+    pub(crate) fn internal_remove_vesting_schedule(&mut self, account_id: &AccountId) {
+        self.internal_adjust_scanned_vesting(account_id, 0);
+        self.vesting_schedules.remove(account_id);
+    }
+
+    // The schedules are counted with their account by the invariant scan
+    fn internal_adjust_scanned_vesting(&mut self, account_id: &AccountId, new_vested: Balance) {
+        if self.internal_is_scanned(account_id) {
+            let old_vested = self.internal_get_vesting_schedule(account_id)
+                .map_or(0, |schedule| schedule.locked_balance() + schedule.unlocked_balance);
+            let totals = &mut self.invariant_scan.as_mut().unwrap().totals;
+            totals.reward = totals.reward + new_vested - old_vested;
+        }
+    }
+
+    pub(crate) fn internal_vest_reward(&mut self, account_id: &AccountId, amount: Balance, vesting: &VestingConfig) {
+        let epoch = env::epoch_height();
+        let mut schedule = self.internal_get_vesting_schedule(account_id).unwrap_or_else(VestingSchedule::new);
+        schedule.add(amount, epoch, vesting);
+        self.internal_save_vesting_schedule(account_id, &schedule);
+    }
+
+    // Block height used for reward, which does not move while the contract is paused
+    // Blocks spent in past pauses are skipped, so no reward is counted for them
+    pub(crate) fn internal_reward_block(&self) -> BlockHeight {
+        let block = if self.paused {
+            self.paused_block
+        } else {
+            env::block_index()
+        };
+        block - self.paused_blocks
+    }
+
+    // Withdraw the unlocked stake and the unstaking balance, the reward is forfeited
+    // The stake of locked positions stays locked with its boost, so a pause is no way out of a lock
+    // Nothing is computed from the reward, so the exit works even if the reward accounting is broken:
+    // the account restarts from the stored reward per share, and the pool is not settled
+    // (so the locked positions keep what they earn since the last settlement of the pool)
+    // Return the withdrawn part, with the settled reward, to rollback if the transfer failed
+    pub(crate) fn internal_emergency_withdraw(&mut self, account_id: AccountId) -> Account {
+        let upgradable_account = self.accounts.get(&account_id).unwrap();
+        let account = Account::from(upgradable_account);
+        let withdrawn_stake = account.stake_balance - account.locked_balance;
+        assert!(withdrawn_stake + account.unstake_balance > 0, "Nothing to withdraw");
+
+        let withdrawn = Account {
+            pre_reward: account.pre_reward,
+            last_block_balance_change: account.last_block_balance_change,
+            membership: account.membership.clone(),
+            stake_balance: withdrawn_stake,
+            unstake_balance: account.unstake_balance,
+            unstake_start_time: account.unstake_start_time,
+            unstake_available_epoch: account.unstake_available_epoch,
+            referrer_id: account.referrer_id.clone(),
+            reward_remainder: account.reward_remainder,
+            locked_balance: 0,
+            boost_balance: 0,
+            reward_per_share_paid: account.reward_per_share_paid,
+            reward_weight: account.reward_weight,
+        };
+        let mut new_account = Account {
+            pre_reward: 0,
+            last_block_balance_change: self.last_block_balance_change,
+            stake_balance: account.locked_balance,
+            unstake_balance: 0,
+            unstake_start_time: 0,
+            unstake_available_epoch: 0,
+            reward_remainder: 0,
+            reward_per_share_paid: self.reward_per_share,
+            ..account
+        };
+        // Set here, so that saving the account does not settle its reward
+        new_account.reward_weight = new_account.weight();
+        let is_staker = new_account.stake_balance > 0;
+        self.internal_save_account(&account_id, new_account);
+
+        if withdrawn_stake > 0 && !is_staker {
+            self.num_staker -= 1;
+        }
+        self.total_stake -= withdrawn_stake;
+        self.total_unstaking -= withdrawn.unstake_balance;
+        if withdrawn_stake > 0 {
+            emit_ft_event("ft_burn", json!({ "owner_id": account_id, "amount": U128(withdrawn_stake) }));
+        }
+
+        withdrawn
     }
 
     // Calculate the reward for users from the previous changes
-    // Generally, we treat that users have deposited twice
+    // Every staked unit earns the same reward per block, accumulated by the pool as the reward per share
     // ==============================================
     // | time  |     t1     |      t2     |   now   |
     // | money |    10$     |      20$    |         |
     // ==============================================
-    // t2 = last_block_balance_change
+    // t2 = last_block_balance_change, where the account paid the reward per share until then
     // now = block_height
-    // reward = (now-t2)*stake*rate
+    // reward = stake*(reward_per_share(now) - reward_per_share(t2))
     //
     // If account=None, the return is for global
     pub(crate) fn internal_calculate_new_reward(&self, account: Option<&Account>) -> Balance {
-        let last_block = if self.paused {
-            self.paused_block
-        }  else {
-            env::block_index()
-        };
+        self.internal_calculate_new_reward_and_remainder(account).0
+    }
 
-        let last_change = if account.is_some() {
-            account.unwrap().last_block_balance_change
-        } else {
-            self.last_block_balance_change
-        };
+    // The product is computed on 256 bits, and the rounding remainder of the last settlement is carried over,
+    // so that frequent updates do not lose reward
+    pub(crate) fn internal_calculate_new_reward_and_remainder(&self, account: Option<&Account>) -> (Balance, u128) {
+        self.internal_calculate_reward_until(account, self.internal_reward_block())
+    }
+
+    // Same as above until the reward block `block`, which can be in the future for projections
+    // Accounts earn with their weight, which includes the boost and the membership multiplier (see `Account::weight`)
+    pub(crate) fn internal_calculate_reward_until(&self, account: Option<&Account>, block: BlockHeight) -> (Balance, u128) {
+        let (reward_per_share, _) = self.internal_reward_per_share_until(block);
+        match account {
+            Some(account) => {
+                // Accounts written before version 3 first earn at the old rate until the migration
+                let weight = account.reward_weight;
+                let legacy_reward = self.legacy_reward_rate.reward_since(weight, account.last_block_balance_change);
+                let (reward, remainder) = calculate_reward(weight, reward_per_share - account.reward_per_share_paid, account.reward_remainder);
+                (legacy_reward.saturating_add(reward), remainder)
+            },
+            None => calculate_reward(self.total_reward_weight, reward_per_share - self.reward_per_share, self.reward_remainder),
+        }
+    }
+
+    // Reward per share at the reward block `block`, with the rounding remainder of its growth
+    // It grows at the current rate since the last settlement of the pool, the rate changes settle it first
+    pub(crate) fn internal_reward_per_share_until(&self, block: BlockHeight) -> (u128, u64) {
+        let cnt_block = block - self.last_block_balance_change;
+        let (growth, remainder) = reward_per_share_growth(self.config.reward_num, self.config.reward_denom, cnt_block, self.reward_per_share_remainder);
+        (self.reward_per_share.saturating_add(growth), remainder)
+    }
 
-        let stake = if account.is_some() {
-            account.unwrap().stake_balance
+    // Reward block after `num_blocks` more blocks, the reward clock does not move while the contract is paused
+    pub(crate) fn internal_projected_reward_block(&self, num_blocks: BlockHeight) -> BlockHeight {
+        if self.paused {
+            self.internal_reward_block()
         } else {
-            self.total_stake
-        };
+            self.internal_reward_block() + num_blocks
+        }
+    }
 
-        let cnt_block = last_block - last_change;
-        let config = self.config;
-        let reward = ((config.reward_num as Balance) * (stake as Balance) * (cnt_block as Balance)) / (config.reward_denom as Balance);
-        reward
+    // Reward of `account` over the next `num_blocks` blocks, if `amount` is staked on top of its balance
+    // The account is settled first, as a stake would do
+    pub(crate) fn internal_estimate_reward(&self, mut account: Account, amount: Balance, num_blocks: BlockHeight) -> Balance {
+        let reward_block = self.internal_reward_block();
+        let (new_reward, reward_remainder) = self.internal_calculate_new_reward_and_remainder(Some(&account));
+        account.pre_reward += new_reward;
+        account.reward_remainder = reward_remainder;
+        account.reward_per_share_paid = self.internal_reward_per_share_until(reward_block).0;
+        account.last_block_balance_change = reward_block;
+        account.stake_balance += amount;
+        account.reward_weight = account.weight();
+
+        self.internal_calculate_reward_until(Some(&account), self.internal_projected_reward_block(num_blocks)).0
+    }
+
+    // Add the accounts of a page of the account index to the sums
+    pub(crate) fn internal_add_account_totals(&self, totals: &mut AccountTotals, from_index: u64, limit: u64) {
+        for index in page_range(self.account_ids.len(), Some(from_index), Some(limit)) {
+            let account_id = self.account_ids.get(index).unwrap();
+            let account = Account::from(self.accounts.get(&account_id).unwrap());
+            totals.add(&self.internal_account_totals(&account_id, &account));
+        }
+    }
+
+    // What a single account adds to the sums, with its vesting schedule
+    pub(crate) fn internal_account_totals(&self, account_id: &AccountId, account: &Account) -> AccountTotals {
+        let new_reward = self.internal_calculate_new_reward(Some(account));
+        let vested = self.internal_get_vesting_schedule(account_id)
+            .map_or(0, |schedule| schedule.locked_balance() + schedule.unlocked_balance);
+        AccountTotals {
+            num_scanned: 1,
+            num_stakers: if account.stake_balance > 0 { 1 } else { 0 },
+            stake_balance: account.stake_balance,
+            unstake_balance: account.unstake_balance,
+            reward: account.pre_reward + new_reward + vested,
+        }
+    }
+
+    // Called by `migrate`, the upgrade is only recorded when the deployed code is the staged one
+    pub(crate) fn internal_record_upgrade(&mut self, previous_version: u128, code_hash: Option<Base58CryptoHash>) {
+        let staged_code = match (get_staged_code(), code_hash) {
+            (Some(staged_code), Some(code_hash)) if staged_code.code_hash == code_hash => staged_code,
+            _ => return,
+        };
+        let record = UpgradeRecord {
+            code_hash: staged_code.code_hash,
+            code_version: env!("CARGO_PKG_VERSION").to_string(),
+            previous_version: U128(previous_version),
+            version: U128(self.version),
+            timestamp: env::block_timestamp(),
+        };
+        self.upgrade_history.push(&record);
+        env::storage_remove(STAGED_CODE_KEY);
+        env::storage_remove(STAGED_CODE_INFO_KEY);
+        emit_event("code_deployed", json!(record));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,150 @@
+use crate::*;
+use near_sdk::serde_json::json;
+
+pub const FT_BALANCE_OF_GAS: Gas = 10_000_000_000_000;
+pub const SOLVENCY_CALLBACK_GAS: Gas = 10_000_000_000_000;
+
+// Sums over a range of the account index
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct AccountTotals {
+    pub num_scanned: u64,
+    pub num_stakers: u64,               // Accounts with a nonzero stake
+    pub stake_balance: Balance,
+    pub unstake_balance: Balance,
+    pub reward: Balance,                // Pending reward, including the vesting schedules
+}
+
+impl AccountTotals {
+    pub fn add(&mut self, other: &AccountTotals) {
+        self.num_scanned += other.num_scanned;
+        self.num_stakers += other.num_stakers;
+        self.stake_balance += other.stake_balance;
+        self.unstake_balance += other.unstake_balance;
+        self.reward += other.reward;
+    }
+
+    // The pending reward of an account grows after it is scanned, so it can exceed what the sum holds
+    pub fn sub(&mut self, other: &AccountTotals) {
+        self.num_scanned -= other.num_scanned;
+        self.num_stakers -= other.num_stakers;
+        self.stake_balance -= other.stake_balance;
+        self.unstake_balance -= other.unstake_balance;
+        self.reward = self.reward.saturating_sub(other.reward);
+    }
+}
+
+// Progress of `check_invariants`, which scans the accounts page by page
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct InvariantScan {
+    pub next_index: u64,
+    pub totals: AccountTotals,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct InvariantReportJson {
+    pub from_index: u64,
+    pub num_accounts: u64,              // Size of the whole account index
+    pub num_scanned: u64,
+    pub num_stakers: u64,
+    pub stake_balance: U128,
+    pub unstake_balance: U128,
+    pub reward: U128,
+    pub total_stake: U128,              // Pool values, to compare with the sums of all pages
+    pub num_staker: U128,
+    pub total_unstaking: U128,
+    pub legacy_stake: U128,             // Stake of the accounts not in the index yet
+    pub num_legacy_accounts: u64,
+}
+
+impl InvariantReportJson {
+    pub fn from(pool: &StakingContract, from_index: u64, totals: &AccountTotals) -> Self {
+        InvariantReportJson {
+            from_index,
+            num_accounts: pool.account_ids.len(),
+            num_scanned: totals.num_scanned,
+            num_stakers: totals.num_stakers,
+            stake_balance: U128(totals.stake_balance),
+            unstake_balance: U128(totals.unstake_balance),
+            reward: U128(totals.reward),
+            total_stake: U128(pool.total_stake),
+            num_staker: U128(pool.num_staker),
+            total_unstaking: U128(pool.total_unstaking),
+            legacy_stake: U128(pool.legacy_stake),
+            num_legacy_accounts: pool.num_legacy_accounts,
+        }
+    }
+}
+
+#[near_bindgen]
+impl StakingContract {
+    // Sums of a page of accounts, the pages add up to the pool values when the accounting is sound
+    pub fn get_invariant_report(&self, from_index: Option<u64>, limit: Option<u64>) -> InvariantReportJson {
+        let from_index = from_index.unwrap_or(0);
+        let mut totals = AccountTotals::default();
+        self.internal_add_account_totals(&mut totals, from_index, limit.unwrap_or(self.account_ids.len()));
+        InvariantReportJson::from(self, from_index, &totals)
+    }
+
+    // Scan the next `limit` accounts, call again until the scan is complete
+    // Then the sums are checked against the pool, and the liabilities against the token balance of the contract
+    // The account index only holds rewritten accounts, the legacy ones are counted by the legacy totals
+    // The solvency is only checked once the account migration is completed, as the unstaking balances and the reward
+    // of the legacy accounts are not known until they are rewritten
+    // Account changes during the scan adjust the sums of the accounts already scanned, so the scan always completes
+    pub fn check_invariants(&mut self, limit: Option<u64>) -> PromiseOrValue<InvariantReportJson> {
+        self.assert_owner();
+        let mut scan = self.invariant_scan.take().unwrap_or(InvariantScan {
+            next_index: 0,
+            totals: AccountTotals::default(),
+        });
+        let from_index = scan.next_index;
+        self.internal_add_account_totals(&mut scan.totals, from_index, limit.unwrap_or(self.account_ids.len()));
+        scan.next_index = scan.totals.num_scanned;
+
+        if scan.next_index < self.account_ids.len() {
+            let report = InvariantReportJson::from(self, from_index, &scan.totals);
+            self.invariant_scan = Some(scan);
+            return PromiseOrValue::Value(report);
+        }
+
+        let totals = scan.totals;
+        assert_eq!(totals.stake_balance + self.legacy_stake, self.total_stake, "Sum of the stake balances does not match the total stake");
+        assert_eq!((totals.num_stakers + self.num_legacy_accounts) as u128, self.num_staker, "Number of stakers does not match");
+        assert_eq!(totals.unstake_balance, self.total_unstaking, "Sum of the unstaking balances does not match the total unstaking");
+
+        assert!(self.account_migration_completed, "Legacy accounts remain, complete the account migration first");
+        let liabilities = totals.stake_balance + self.legacy_stake + totals.unstake_balance + totals.reward + self.airdrop_balance;
+        ext_ft::ft_balance_of(
+            env::current_account_id(),
+            &self.ft_contract_id,
+            0,
+            FT_BALANCE_OF_GAS
+        ).then(ext_self::ft_solvency_callback(
+            U128(liabilities),
+            &env::current_account_id(),
+            0,
+            SOLVENCY_CALLBACK_GAS
+        )).into()
+    }
+
+    #[private]
+    pub fn ft_solvency_callback(&mut self, liabilities: U128) -> U128 {
+        assert_eq!(env::promise_results_count(), 1, "Too many result of promise");
+        let balance: U128 = match env::promise_result(0) {
+            PromiseResult::Successful(value) => near_sdk::serde_json::from_slice(&value).expect("Invalid balance"),
+            _ => env::panic("Cannot read the token balance".as_bytes()),
+        };
+        assert!(
+            balance.0 >= liabilities.0,
+            "Contract is insolvent: balance {} is lower than liabilities {}",
+            balance.0,
+            liabilities.0
+        );
+        emit_event("invariants_checked", json!({
+            "balance": balance,
+            "liabilities": liabilities,
+        }));
+        U128(balance.0 - liabilities.0)
+    }
+}
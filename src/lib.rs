@@ -1,20 +1,36 @@
 use near_sdk::*;
 use near_sdk::borsh::{self,BorshDeserialize,BorshSerialize};
-use near_sdk::collections::LookupMap;
-use near_sdk::json_types::{U128};
+use near_sdk::collections::{LookupMap, UnorderedMap, UnorderedSet, Vector};
+use near_sdk::json_types::{Base58CryptoHash, Base64VecU8, U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
 // use ::constant::{ONE_YOCTO};
 
-use crate::config::*;
-use crate::account::*;
+pub use crate::config::*;
+pub use crate::account::*;
 use crate::types::*;
 use crate::utils::*;
-use crate::enumeration::*;
-use crate::pool::*;
+pub use crate::pool::*;
 use crate::staking_contract_v1::*;
+use crate::staking_contract_v2::*;
+use crate::config_v1::*;
 use crate::account_v1::*;
+use crate::account_v2::*;
 use crate::upgradable_account::*;
-use crate::core_impl::*;
+use crate::vesting::*;
+use crate::events::*;
+use crate::roles::*;
+use crate::timelock::*;
+use crate::migration::*;
+use crate::upgrade::*;
+use crate::invariants::*;
+pub use crate::storage::*;
+pub use crate::transfer::*;
+pub use crate::receipt_token::*;
+pub use crate::positions::*;
+pub use crate::nft::*;
+pub use crate::airdrop::*;
+pub use crate::close::*;
+pub use crate::core_impl::*;
 
 mod config;
 mod account;
@@ -24,8 +40,27 @@ mod internal;
 mod enumeration;
 mod pool;
 mod staking_contract_v1;
+mod staking_contract_v2;
+mod config_v1;
+mod migration;
+mod upgrade;
+mod invariants;
+mod storage;
+mod transfer;
+mod receipt_token;
+mod positions;
+mod nft;
+mod airdrop;
+mod close;
+mod rescue;
 mod account_v1;
+mod account_v2;
 mod upgradable_account;
+mod vesting;
+mod events;
+mod owner;
+mod roles;
+mod timelock;
 mod core_impl;
 
 // Using `near_bindgen` marco, to notify the smart contract
@@ -46,7 +81,47 @@ pub struct StakingContract {
     pub accounts: LookupMap<AccountId, UpgradableAccount>,    
     pub paused: bool,                               
     pub paused_block: BlockHeight,                  
-    pub version: u128,                               // New field to update (V2)
+    pub vesting_schedules: LookupMap<AccountId, VestingSchedule>,
+    pub referees: LookupMap<AccountId, UnorderedSet<AccountId>>,
+    pub referral_earnings: LookupMap<AccountId, Balance>,
+    pub allowlist_enabled: bool,
+    pub allowlist: UnorderedSet<AccountId>,
+    pub denylist: UnorderedSet<AccountId>,
+    pub paused_blocks: BlockHeight,                 // Number of blocks spent in past pauses
+    pub roles: LookupMap<Role, UnorderedSet<AccountId>>,
+    pub pending_actions: UnorderedMap<u64, PendingAction>,
+    pub next_action_id: u64,
+    pub action_delay: u64,                          // Nanoseconds between queuing and executing an admin action
+    pub num_legacy_accounts: u64,                   // Stakers not yet rewritten in the current layout
+    pub upgrade_history: Vector<UpgradeRecord>,
+    pub account_ids: Vector<AccountId>,             // Index of the accounts, as `accounts` cannot be iterated
+    pub account_indices: LookupMap<AccountId, u64>, // Position of each account in `account_ids`
+    pub invariant_scan: Option<InvariantScan>,
+    pub reward_remainder: u128,                     // Rounding remainder of the pool reward, in units of `1 / REWARD_PER_SHARE_SCALE`
+    pub total_unstaking: Balance,                   // Unstaked tokens not withdrawn yet
+    pub total_withdrawn: Balance,
+    pub pending_operations: LookupMap<AccountId, PendingOperation>,
+    pub prepaid_storage: LookupMap<AccountId, Balance>,  // NEAR paying the registration of beneficiaries
+    pub storage_pool: Balance,                      // NEAR funded by the owner for automatic registrations
+    pub storage_pool_usage: LookupMap<AccountId, Balance>,
+    pub receipt_token_metadata: ReceiptTokenMetadata,
+    pub positions: UnorderedMap<TokenId, Position>,
+    pub positions_per_owner: LookupMap<AccountId, UnorderedSet<TokenId>>,
+    pub next_position_id: u64,
+    pub total_boost: Balance,                       // Sum of the boost balances, earning reward as if they were staked
+    pub airdrop_balance: Balance,                   // Tokens funded by the owner, not credited yet by `airdrop_stake`
+    pub total_prepaid_storage: Balance,             // Sum of `prepaid_storage`, owed to the senders
+    pub reward_per_share: u128,                     // Reward of one staked unit since version 3, scaled by `REWARD_PER_SHARE_SCALE`
+    pub reward_per_share_remainder: u64,            // Rounding remainder of `reward_per_share`, in units of `1 / reward_denom`
+    pub legacy_reward_rate: LegacyRewardRate,       // Rate of the accounts written before version 3, until the migration
+    pub total_reward_weight: Balance,               // Sum of the weights of the accounts, earning the pool reward
+    pub legacy_stake: Balance,                      // Stake of the accounts not yet rewritten in the current layout
+    pub account_storage_usage: StorageUsage,        // Bytes of the largest registration, measured at init and by every migration
+    pub storage_charges: LookupMap<AccountId, StorageCharge>,  // Cost of the registration of each account and its payer
+    pub pending_storage_refunds: Balance,           // Storage of the accounts closing, owed until their transfer is resolved
+    pub account_migration_completed: bool,          // Set by an admin once no legacy account is left (see `complete_account_migration`)
+    pub pending_transfers: Balance,                 // Staked tokens of the transfers in flight, until their callback
+    pub version: u128,                              // Layout version, MUST stay the last field (see `migration.rs`)
 }
 
 #[near_bindgen]
@@ -70,7 +145,8 @@ impl StakingContract {
         ft_contract_id: AccountId,
         config: Config
     ) -> Self {
-        StakingContract { 
+        config.assert_valid();
+        let mut contract = StakingContract { 
             owner_id, 
             ft_contract_id, 
             config,
@@ -82,31 +158,87 @@ impl StakingContract {
             accounts: LookupMap::new(StorageKey::AccountKey),
             paused: false,
             paused_block: 0,
-            version: 2,
-        }
+            vesting_schedules: LookupMap::new(StorageKey::VestingKey),
+            referees: LookupMap::new(StorageKey::RefereesKey),
+            referral_earnings: LookupMap::new(StorageKey::ReferralEarningKey),
+            allowlist_enabled: false,
+            allowlist: UnorderedSet::new(StorageKey::AllowlistKey),
+            denylist: UnorderedSet::new(StorageKey::DenylistKey),
+            paused_blocks: 0,
+            roles: LookupMap::new(StorageKey::RolesKey),
+            pending_actions: UnorderedMap::new(StorageKey::PendingActionKey),
+            next_action_id: 0,
+            action_delay: DEFAULT_ACTION_DELAY,
+            num_legacy_accounts: 0,
+            upgrade_history: Vector::new(StorageKey::UpgradeHistoryKey),
+            account_ids: Vector::new(StorageKey::AccountIdsKey),
+            account_indices: LookupMap::new(StorageKey::AccountIndexKey),
+            invariant_scan: None,
+            reward_remainder: 0,
+            total_unstaking: 0,
+            total_withdrawn: 0,
+            pending_operations: LookupMap::new(StorageKey::PendingOperationKey),
+            prepaid_storage: LookupMap::new(StorageKey::PrepaidStorageKey),
+            storage_pool: 0,
+            storage_pool_usage: LookupMap::new(StorageKey::StoragePoolUsageKey),
+            receipt_token_metadata: ReceiptTokenMetadata::default(),
+            positions: UnorderedMap::new(StorageKey::PositionKey),
+            positions_per_owner: LookupMap::new(StorageKey::PositionsPerOwnerKey),
+            next_position_id: 0,
+            total_boost: 0,
+            airdrop_balance: 0,
+            total_prepaid_storage: 0,
+            reward_per_share: 0,
+            reward_per_share_remainder: 0,
+            legacy_reward_rate: LegacyRewardRate {
+                reward_num: config.reward_num,
+                reward_denom: config.reward_denom,
+                until_block: env::block_index(),
+            },
+            total_reward_weight: 0,
+            legacy_stake: 0,
+            account_storage_usage: 0,
+            storage_charges: LookupMap::new(StorageKey::StorageChargeKey),
+            pending_storage_refunds: 0,
+            account_migration_completed: true,
+            pending_transfers: 0,
+            version: CURRENT_VERSION,
+        };
+        contract.account_storage_usage = contract.internal_measure_account_storage_usage();
+        contract
     }
 
     // Storing data on-chain require a small amount of NEAR (since using storage)
     // Instead of using ourself money, we make the user to deposit them
     // This is the `reserved_near` in wallet
+    // The referrer (if any) is only recorded when the account is created, and only the account itself can choose it
+    // NEP-145: an account only needs its registration, so the rest of the deposit is always refunded,
+    // whatever `registration_only` (see `storage.rs`)
     #[payable]
-    pub fn storage_deposit(&mut self, account_id: Option<AccountId>) {
+    #[allow(unused_variables, clippy::redundant_closure)]
+    pub fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+        referrer_id: Option<AccountId>
+    ) -> StorageBalance {
         assert_at_least_one_yocto();
         
         let account = account_id.unwrap_or_else(|| env::predecessor_account_id());
+        assert!(referrer_id.is_none() || account == env::predecessor_account_id(), "Only the account itself can choose its referrer");
         let account_stake = self.accounts.get(&account);
         if account_stake.is_some() {
             // Refund all tokens
             refund_deposit(0)
         } else {
             // Create new account
-            let storage_usage_before = env::storage_usage();
-            self.internal_create_account(account);
-            let storage_usage_after = env::storage_usage();
+            self.assert_allowed(&account);
+            let storage_used = self.internal_register_account(account.clone(), referrer_id);
 
             // Refund the rest tokens
-            refund_deposit(storage_usage_after - storage_usage_before);
+            refund_deposit(storage_used);
         }
+        self.storage_balance_of(account).unwrap()
     }
 
     pub fn exist_account(&self, account_id: AccountId) -> bool {
@@ -120,31 +252,6 @@ impl StakingContract {
     pub fn get_version(&self) -> U128 {
         U128(self.version)
     }
-
-    // This is to upgrade the staking contract from v1 to v2
-    // Use the private macro to avoid others people calling it (only the contract can call)
-    // To migrate, use the command `near dev-deploy path --initFunction migrate --initArgs '{}'`
-    //
-    // This function MUST BE REMOVED after migrating to be secured
-    #[private]
-    #[init(ignore_state)]
-    pub fn migrate() -> Self {
-        let old_contract: StakingContractV1 = env::state_read().expect("Cannot read old contract");
-        StakingContract { 
-            owner_id: old_contract.owner_id,
-            ft_contract_id: old_contract.ft_contract_id,
-            config: old_contract.config,
-            total_stake: old_contract.total_stake,
-            total_paid_reward: old_contract.total_paid_reward,
-            num_staker: old_contract.num_staker,
-            pre_reward: old_contract.pre_reward,
-            last_block_balance_change: old_contract.last_block_balance_change,
-            accounts: old_contract.accounts, 
-            paused: old_contract.paused,
-            paused_block: old_contract.paused_block,
-            version: 2
-        }
-    }
 }
 
 // =========================== Unit Test =============================
@@ -153,7 +260,28 @@ impl StakingContract {
 mod tests {
     use super::*;
     use near_sdk::{testing_env, MockedBlockchain};
-    use near_sdk::test_utils::{VMContextBuilder, accounts};
+    use near_sdk::test_utils::{VMContextBuilder, accounts, get_logs};
+    use near_sdk::json_types::ValidAccountId;
+    use proptest::prelude::*;
+
+    // Borsh serialized states written by older versions of the contract
+    // owner.near / ft.near / config (500, 100000, 1) / stake 1000 / paid 10 / 1 staker / reward 20 / block 50
+    const STATE_V1: &str = "CgAAAG93bmVyLm5lYXIHAAAAZnQubmVhcvQBAACghgEAAAAAAAEAAAAAAAAA6AMAAAAAAAAAAAAAAAAAAAoAAAAAAAAAAAAAAAAAAAABAAAAAAAAAAAAAAAAAAAAFAAAAAAAAAAAAAAAAAAAADIAAAAAAAAAAQAAAAAAAAAAAAAAAAA=";
+    const STATE_V2: &str = "CgAAAG93bmVyLm5lYXIHAAAAZnQubmVhcvQBAACghgEAAAAAAAEAAAAAAAAA6AMAAAAAAAAAAAAAAAAAAAoAAAAAAAAAAAAAAAAAAAABAAAAAAAAAAAAAAAAAAAAFAAAAAAAAAAAAAAAAAAAADIAAAAAAAAAAQAAAAAAAAAAAAAAAAACAAAAAAAAAAAAAAAAAAAA";
+    // Account stored by version 1: stake 500 / reward 7 / block 40
+    const ACCOUNT_V1: &str = "APQBAAAAAAAAAAAAAAAAAAAHAAAAAAAAAAAAAAAAAAAAKAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA==";
+    // Account stored by version 2 as `Current`: stake 1000 / reward 20 / block 50 / Standard membership
+    const ACCOUNT_V2: &str = "AegDAAAAAAAAAAAAAAAAAAAUAAAAAAAAAAAAAAAAAAAAMgAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAE=";
+
+    fn write_fixture_state(state: &str) {
+        env::storage_write(b"STATE", &base64::decode(state).unwrap());
+    }
+
+    fn write_fixture_account(account_id: &str, account: &str) {
+        let mut key = StorageKey::AccountKey.into_storage_key();
+        key.extend(account_id.to_string().try_to_vec().unwrap());
+        env::storage_write(&key, &base64::decode(account).unwrap());
+    }
 
     fn get_context(is_view: bool) -> VMContextBuilder {
         let mut builder = VMContextBuilder::new();
@@ -166,7 +294,30 @@ mod tests {
         builder
     }
 
+    // Contract owned by accounts(0), staking the token of accounts(2)
+    fn setup_contract(context: &VMContextBuilder, config: Config) -> StakingContract {
+        testing_env!(context.build());
+        StakingContract::new(accounts(0).to_string(), accounts(2).to_string(), config)
+    }
+
+    fn register(contract: &mut StakingContract, context: &mut VMContextBuilder, account_id: ValidAccountId) {
+        testing_env!(context.predecessor_account_id(account_id).attached_deposit(10u128.pow(24)).build());
+        contract.storage_deposit(None, None, None);
+    }
+
+    // Stake through the token contract, as `ft_transfer_call` does
+    fn stake(contract: &mut StakingContract, context: &mut VMContextBuilder, account_id: ValidAccountId, amount: Balance) {
+        testing_env!(context.predecessor_account_id(accounts(2)).attached_deposit(0).build());
+        contract.ft_on_transfer(account_id.to_string(), U128(amount), "".to_string());
+    }
+
+    fn register_and_stake(contract: &mut StakingContract, context: &mut VMContextBuilder, account_id: ValidAccountId, amount: Balance) {
+        register(contract, context, account_id.clone());
+        stake(contract, context, account_id, amount);
+    }
+
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_init_contract() {
         let context = get_context(false);
         testing_env!(context.build());
@@ -175,6 +326,7 @@ mod tests {
             reward_num: 500,
             reward_denom: 100000,
             num_epoch_unlock: 1,
+            ..Config::default()
         };
 
         let contract = StakingContract::new(
@@ -188,4 +340,1366 @@ mod tests {
         assert_eq!(config.reward_num, contract.config.reward_num);
         assert_eq!(contract.paused, false);
     }   
-}
\ No newline at end of file
+
+    #[test]
+    fn test_harvest_with_vesting() {
+        let mut context = get_context(false);
+
+        let config: Config = Config {
+            vesting: Some(VestingConfig { cliff_epochs: 2, duration_epochs: 4 }),
+            ..Config::default()
+        };
+        let mut contract = setup_contract(&context, config);
+
+        register(&mut contract, &mut context, accounts(1));
+
+        stake(&mut contract, &mut context, accounts(1), 10u128.pow(24));
+
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(1).block_index(1000).build());
+        contract.harvest();
+
+        let vesting = contract.get_vesting_info(accounts(1).to_string()).unwrap();
+        let reward = contract.config.reward_num as Balance * 10u128.pow(24) * 1000 / contract.config.reward_denom as Balance;
+        assert_eq!(vesting.locked_balance.0, reward);
+        assert_eq!(vesting.claimable_balance.0, 0);
+        assert_eq!(contract.get_account_info(&accounts(1).to_string()).reward, 0);
+
+        // Half of the duration has passed right at the cliff
+        testing_env!(context.epoch_height(2).build());
+        let vesting = contract.get_vesting_info(accounts(1).to_string()).unwrap();
+        assert_eq!(vesting.claimable_balance.0, reward / 2);
+
+        testing_env!(context.epoch_height(4).build());
+        let vesting = contract.get_vesting_info(accounts(1).to_string()).unwrap();
+        assert_eq!(vesting.claimable_balance.0, reward);
+        assert_eq!(vesting.locked_balance.0, 0);
+    }
+
+    #[test]
+    fn test_harvest_does_not_delay_vesting_reward() {
+        let mut context = get_context(false);
+
+        let config: Config = Config {
+            vesting: Some(VestingConfig { cliff_epochs: 2, duration_epochs: 4 }),
+            ..Config::default()
+        };
+        let mut contract = setup_contract(&context, config);
+
+        register(&mut contract, &mut context, accounts(1));
+
+        stake(&mut contract, &mut context, accounts(1), 10u128.pow(24));
+
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(1).block_index(1000).build());
+        contract.harvest();
+        let reward = contract.config.reward_num as Balance * 10u128.pow(24) * 1000 / contract.config.reward_denom as Balance;
+
+        // The second harvest vests on its own tranche, the first one keeps its cliff and end
+        testing_env!(context.block_index(2000).epoch_height(2).build());
+        contract.harvest();
+        let vesting = contract.get_vesting_info(accounts(1).to_string()).unwrap();
+        assert_eq!(vesting.claimable_balance.0, reward / 2);
+        assert_eq!(vesting.locked_balance.0, reward / 2 + reward);
+        assert_eq!(vesting.tranches.len(), 2);
+        assert_eq!(vesting.end_epoch, Some(6));
+
+        testing_env!(context.epoch_height(4).build());
+        let vesting = contract.get_vesting_info(accounts(1).to_string()).unwrap();
+        assert_eq!(vesting.claimable_balance.0, reward + reward / 2);
+        assert_eq!(vesting.tranches.len(), 1);
+
+        testing_env!(context.epoch_height(6).build());
+        let vesting = contract.get_vesting_info(accounts(1).to_string()).unwrap();
+        assert_eq!(vesting.claimable_balance.0, 2 * reward);
+        assert_eq!(vesting.locked_balance.0, 0);
+    }
+
+    #[test]
+    fn test_referral_reward() {
+        let mut context = get_context(false);
+
+        let config: Config = Config {
+            referral_reward_bps: 1000,
+            ..Config::default()
+        };
+        let mut contract = setup_contract(&context, config);
+
+        register(&mut contract, &mut context, accounts(1));
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.storage_deposit(None, None, Some(accounts(1).to_string()));
+        assert_eq!(contract.get_referees(accounts(1).to_string(), None, None), vec![accounts(3).to_string()]);
+
+        stake(&mut contract, &mut context, accounts(3), 10u128.pow(24));
+
+        // Settling the referee reward pays the referrer without touching the referee
+        testing_env!(context.block_index(1000).build());
+        contract.ft_on_transfer(accounts(3).to_string(), U128(1), "".to_string());
+
+        let reward = contract.config.reward_num as Balance * 10u128.pow(24) * 1000 / contract.config.reward_denom as Balance;
+        assert_eq!(contract.get_account_info(&accounts(3).to_string()).reward, reward);
+        assert_eq!(contract.get_account_info(&accounts(1).to_string()).reward, reward / 10);
+        assert_eq!(contract.get_referral_info(accounts(1).to_string()).referral_earnings.0, reward / 10);
+    }
+
+    #[test]
+    fn test_referrer_only_chosen_by_the_account_at_registration() {
+        let mut context = get_context(false);
+        let config: Config = Config {
+            auto_register: Some(AutoRegisterConfig { max_storage_per_account: U128(10u128.pow(24)) }),
+            ..Config::default()
+        };
+        let mut contract = setup_contract(&context, config);
+
+        register(&mut contract, &mut context, accounts(1));
+        contract.storage_deposit(Some(accounts(3).to_string()), None, None);
+
+        // A registered account which stakes for the first time does not get a referrer
+        let msg = format!("{{\"referrer_id\":\"{}\"}}", accounts(1));
+        testing_env!(context.predecessor_account_id(accounts(2)).attached_deposit(0).build());
+        contract.ft_on_transfer(accounts(3).to_string(), U128(1000), msg.clone());
+        assert!(contract.get_account_info(&accounts(3).to_string()).referrer_id.is_none());
+
+        // Neither does a beneficiary, staked for by another account
+        testing_env!(context.predecessor_account_id(accounts(3)).attached_deposit(10u128.pow(24)).build());
+        contract.prepay_storage();
+        let msg = format!("{{\"referrer_id\":\"{}\",\"beneficiary_id\":\"{}\"}}", accounts(1), accounts(4));
+        testing_env!(context.predecessor_account_id(accounts(2)).attached_deposit(0).build());
+        contract.ft_on_transfer(accounts(3).to_string(), U128(1000), msg);
+        assert!(contract.get_account_info(&accounts(4).to_string()).referrer_id.is_none());
+        assert!(contract.get_referees(accounts(1).to_string(), None, None).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the account itself can choose its referrer")]
+    fn test_storage_deposit_for_another_account_with_referrer() {
+        let mut context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+
+        register(&mut contract, &mut context, accounts(1));
+        contract.storage_deposit(Some(accounts(3).to_string()), None, Some(accounts(1).to_string()));
+    }
+
+    #[test]
+    fn test_storage_management_standard() {
+        let mut context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+        assert!(contract.storage_balance_of(accounts(1).to_string()).is_none());
+
+        // The deposit of the minimum bound always covers the registration, with the longest ids and a referrer
+        let bounds = contract.storage_balance_bounds();
+        assert_eq!(bounds.max, Some(bounds.min));
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(bounds.min.0).build());
+        contract.storage_deposit(None, None, None);
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        let storage_usage = env::storage_usage();
+        contract.storage_deposit(None, Some(true), Some(accounts(1).to_string()));
+        let storage_cost = env::storage_byte_cost() * Balance::from(env::storage_usage() - storage_usage);
+        assert!(storage_cost <= bounds.min.0);
+        // The balance is what the registration actually cost
+        let balance = contract.storage_balance_of(accounts(3).to_string()).unwrap();
+        assert_eq!((balance.total, balance.available), (U128(storage_cost), U128(0)));
+
+        // An empty account is unregistered, one with funds is not
+        stake(&mut contract, &mut context, accounts(3), 1000);
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(1).build());
+        assert!(contract.storage_unregister(None));
+        assert!(contract.storage_balance_of(accounts(1).to_string()).is_none());
+        assert!(!contract.storage_unregister(None));
+    }
+
+    #[test]
+    #[should_panic(expected = "The account still has funds, use close_account")]
+    fn test_storage_unregister_with_funds() {
+        let mut context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+
+        register_and_stake(&mut contract, &mut context, accounts(1), 1000);
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(1).build());
+        contract.storage_unregister(None);
+    }
+
+    #[test]
+    fn test_denylist_can_still_unstake() {
+        let mut context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+
+        register_and_stake(&mut contract, &mut context, accounts(1), 1000);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.add_to_denylist(vec![accounts(1).to_string()]);
+        assert_eq!(contract.get_denylist(None, None), vec![accounts(1).to_string()]);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(1).build());
+        contract.unstake(U128(1000));
+        assert_eq!(contract.get_account_info(&accounts(1).to_string()).unstake_balance, 1000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Account is not in the allowlist")]
+    fn test_allowlist_blocks_registration() {
+        let mut context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+        contract.set_allowlist_enabled(true);
+
+        register(&mut contract, &mut context, accounts(1));
+    }
+
+    #[test]
+    fn test_stake_caps_partial_acceptance() {
+        let mut context = get_context(false);
+
+        let config: Config = Config {
+            max_stake_per_account: Some(U128(1000)),
+            max_total_stake: Some(U128(1500)),
+            min_stake_amount: Some(U128(100)),
+            ..Config::default()
+        };
+        let mut contract = setup_contract(&context, config);
+
+        register(&mut contract, &mut context, accounts(1));
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.storage_deposit(None, None, None);
+
+        let unused = |value: PromiseOrValue<U128>| match value {
+            PromiseOrValue::Value(amount) => amount.0,
+            PromiseOrValue::Promise(_) => unreachable!(),
+        };
+
+        testing_env!(context.predecessor_account_id(accounts(2)).attached_deposit(0).build());
+        assert_eq!(unused(contract.ft_on_transfer(accounts(1).to_string(), U128(1200), "".to_string())), 200);
+        assert_eq!(unused(contract.ft_on_transfer(accounts(3).to_string(), U128(800), "".to_string())), 300);
+        assert_eq!(unused(contract.ft_on_transfer(accounts(3).to_string(), U128(50), "".to_string())), 50);
+
+        assert_eq!(contract.get_account_info(&accounts(1).to_string()).stake_balance, 1000);
+        assert_eq!(contract.get_account_info(&accounts(3).to_string()).stake_balance, 500);
+        assert_eq!(contract.total_stake, 1500);
+    }
+
+    #[test]
+    fn test_emergency_withdraw_rollback() {
+        let mut context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+
+        register_and_stake(&mut contract, &mut context, accounts(1), 1000);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).block_index(100).build());
+        contract.pause();
+
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(1).block_index(200).build());
+        let old_account = Account::from(contract.accounts.get(&accounts(1).to_string()).unwrap());
+        contract.emergency_withdraw();
+        let account = contract.get_account_info(&accounts(1).to_string());
+        assert_eq!(account.stake_balance, 0);
+        assert_eq!(account.reward, 0);
+        assert_eq!(contract.total_stake, 0);
+        assert_eq!(contract.num_staker, 0);
+
+        // The transfer failed, so the account is restored
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).attached_deposit(0).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        assert_eq!(contract.ft_emergency_withdraw_callback(accounts(1).to_string(), old_account).0, 0);
+        assert_eq!(contract.get_account_info(&accounts(1).to_string()).stake_balance, 1000);
+        assert_eq!(contract.total_stake, 1000);
+        assert_eq!(contract.num_staker, 1);
+
+        // No reward is counted for the paused blocks
+        testing_env!(context.predecessor_account_id(accounts(0)).block_index(300).build());
+        contract.unpause();
+        testing_env!(context.block_index(400).build());
+        let reward = contract.config.reward_num as Balance * 1000 * 200 / contract.config.reward_denom as Balance;
+        assert_eq!(contract.get_pool_info().total_reward.0, reward);
+    }
+
+    #[test]
+    fn test_emergency_withdraw_does_not_settle_reward() {
+        let mut context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+
+        register_and_stake(&mut contract, &mut context, accounts(1), 10u128.pow(24));
+
+        testing_env!(context.predecessor_account_id(accounts(0)).block_index(100).build());
+        contract.pause();
+
+        // The exit computes no reward: the pool is not settled, and the forfeited reward is not counted
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(1).block_index(200).build());
+        let old_account = Account::from(contract.accounts.get(&accounts(1).to_string()).unwrap());
+        contract.emergency_withdraw();
+        assert_eq!((contract.reward_per_share, contract.last_block_balance_change), (0, 0));
+        assert_eq!(contract.total_reward_weight, 0);
+        assert_eq!(contract.get_pool_info().total_reward.0, 0);
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).attached_deposit(0).block_index(300).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        // The stake is restored from the settled pool, its unsettled reward stays forfeited
+        contract.ft_emergency_withdraw_callback(accounts(1).to_string(), old_account);
+        assert_eq!(contract.last_block_balance_change, 100);
+        assert_eq!(contract.total_reward_weight, 10u128.pow(24));
+        assert_eq!(contract.get_account_info(&accounts(1).to_string()).reward, 0);
+    }
+
+    #[test]
+    fn test_withdraw_rollback_keeps_referral_reward() {
+        let mut context = get_context(false);
+        let config = Config { referral_reward_bps: 1_000, ..Config::default() };
+        let mut contract = setup_contract(&context, config);
+
+        register(&mut contract, &mut context, accounts(1));
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.storage_deposit(None, None, Some(accounts(1).to_string()));
+        stake(&mut contract, &mut context, accounts(1), 1000);
+        contract.ft_on_transfer(accounts(3).to_string(), U128(10u128.pow(24)), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(1).build());
+        contract.unstake(U128(1000));
+
+        testing_env!(context.predecessor_account_id(accounts(1)).epoch_height(1).build());
+        let old_account = Account::from(contract.accounts.get(&accounts(1).to_string()).unwrap());
+        contract.withdraw();
+        assert_eq!(contract.get_pending_operation(accounts(1).to_string()), Some(PendingOperation::Withdraw));
+
+        // The referee pays a referral reward while the transfer is in flight
+        testing_env!(context.predecessor_account_id(accounts(3)).block_index(100).build());
+        contract.unstake(U128(10u128.pow(24)));
+        let referral_reward = contract.get_account_info(&accounts(1).to_string()).reward;
+        assert!(referral_reward > 0);
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).attached_deposit(0).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        contract.ft_withdraw_callback(accounts(1).to_string(), old_account);
+        let account = contract.get_account_info(&accounts(1).to_string());
+        assert_eq!((account.unstake_balance, account.reward), (1000, referral_reward));
+        assert_eq!(contract.total_unstaking, 1000 + 10u128.pow(24));
+    }
+
+    #[test]
+    fn test_admin_clears_pending_operation() {
+        let mut context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+
+        register(&mut contract, &mut context, accounts(1));
+        contract.internal_lock_account(&accounts(1).to_string(), PendingOperation::Harvest);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(0).build());
+        contract.clear_pending_operation(accounts(1).to_string());
+        assert!(contract.get_pending_operation(accounts(1).to_string()).is_none());
+        assert!(get_logs().iter().any(|log| log.contains("\"pending_operation_cleared\"")));
+    }
+
+    #[test]
+    fn test_reward_rate_change_is_not_retroactive() {
+        let mut context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+
+        register_and_stake(&mut contract, &mut context, accounts(1), 10u128.pow(24));
+
+        // The rate doubles at block 100, the account is not touched
+        let config = contract.config;
+        let new_config = Config { reward_num: config.reward_num * 2, ..config };
+        testing_env!(context.predecessor_account_id(accounts(0)).block_index(100).build());
+        let action_id = contract.queue_action(AdminAction::UpdateConfig { config: new_config });
+        testing_env!(context.block_timestamp(DEFAULT_ACTION_DELAY).build());
+        contract.execute_action(action_id);
+
+        testing_env!(context.block_index(200).build());
+        let reward = config.reward_num as Balance * 10u128.pow(24) * 100 / config.reward_denom as Balance;
+        assert_eq!(contract.get_account_info(&accounts(1).to_string()).reward, reward + 2 * reward);
+        assert_eq!(contract.get_pool_info().total_reward.0, reward + 2 * reward);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner or a member of the Admin role can call this function")]
+    fn test_pauser_role_cannot_manage_access() {
+        let mut context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+        contract.grant_role(Role::Pauser, accounts(3).to_string());
+        assert_eq!(contract.get_role_members(Role::Pauser, None, None), vec![accounts(3).to_string()]);
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.pause();
+        assert!(contract.is_paused());
+
+        contract.add_to_denylist(vec![accounts(1).to_string()]);
+    }
+
+    #[test]
+    fn test_timelocked_config_update() {
+        let mut context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+
+        let config = Config { num_epoch_unlock: 5, ..Config::default() };
+        let action_id = contract.queue_action(AdminAction::UpdateConfig { config });
+        assert_eq!(contract.get_pending_actions(None, None).len(), 1);
+        assert_eq!(contract.config.num_epoch_unlock, 1);
+
+        testing_env!(context.block_timestamp(DEFAULT_ACTION_DELAY).build());
+        contract.execute_action(action_id);
+        assert_eq!(contract.config.num_epoch_unlock, 5);
+        assert!(contract.get_pending_actions(None, None).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Reward denominator must be positive")]
+    fn test_queue_invalid_config() {
+        let context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+
+        let config = Config { reward_denom: 0, ..Config::default() };
+        contract.queue_action(AdminAction::UpdateConfig { config });
+    }
+
+    #[test]
+    #[should_panic(expected = "Lock bounds must satisfy 0 < min_lock_epochs <= max_lock_epochs")]
+    fn test_init_with_invalid_config() {
+        let context = get_context(false);
+        let config = Config {
+            position: Some(PositionConfig { min_lock_epochs: 5, max_lock_epochs: 1, max_boost_bps: 5_000 }),
+            ..Config::default()
+        };
+        setup_contract(&context, config);
+    }
+
+    #[test]
+    #[should_panic(expected = "Action is still timelocked")]
+    fn test_timelocked_action_too_early() {
+        let context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+
+        let action_id = contract.queue_action(AdminAction::TransferOwnership { owner_id: accounts(1).to_string() });
+        contract.execute_action(action_id);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this function")]
+    fn test_admin_cannot_queue_ownership_transfer() {
+        let mut context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+        contract.grant_role(Role::Admin, accounts(1).to_string());
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.queue_action(AdminAction::TransferOwnership { owner_id: accounts(1).to_string() });
+    }
+
+    #[test]
+    fn test_migrate_from_v1_state() {
+        let mut context = get_context(false);
+        testing_env!(context.block_index(250).build());
+        write_fixture_state(STATE_V1);
+
+        let contract = StakingContract::migrate(None);
+        assert_eq!(contract.version, CURRENT_VERSION);
+        assert_eq!(contract.owner_id, "owner.near".to_string());
+        assert_eq!(contract.ft_contract_id, "ft.near".to_string());
+        assert_eq!(contract.config.reward_num, 500);
+        assert_eq!(contract.config.reward_denom, 100000);
+        assert_eq!(contract.total_stake, 1000);
+        assert_eq!(contract.total_paid_reward, 10);
+        assert_eq!(contract.num_staker, 1);
+        // The pool reward is settled at the old rate until the migration
+        assert_eq!(contract.pre_reward, 20 + 1000 * 500 * 200 / 100000);
+        assert_eq!(contract.last_block_balance_change, 250);
+        assert_eq!(contract.legacy_reward_rate.until_block, 250);
+    }
+
+    #[test]
+    fn test_migrate_from_v2_state() {
+        let mut context = get_context(false);
+        testing_env!(context.block_index(250).build());
+        write_fixture_state(STATE_V2);
+        write_fixture_account("alice.near", ACCOUNT_V2);
+
+        let contract = StakingContract::migrate(None);
+        assert_eq!(contract.version, CURRENT_VERSION);
+        assert_eq!(contract.total_stake, 1000);
+        assert_eq!(contract.action_delay, DEFAULT_ACTION_DELAY);
+        assert_eq!(contract.total_prepaid_storage, 0);
+
+        let account = Account::from(contract.accounts.get(&"alice.near".to_string()).unwrap());
+        assert_eq!(account.stake_balance, 1000);
+        assert_eq!(account.pre_reward, 20);
+        assert_eq!(account.last_block_balance_change, 50);
+        assert!(matches!(account.membership, Membership::Standard));
+        assert!(account.referrer_id.is_none());
+
+        // The account earns at the old rate until the migration, then from the reward per share
+        assert_eq!(contract.get_account_info(&"alice.near".to_string()).reward, 20 + 1000);
+        testing_env!(context.block_index(450).build());
+        assert_eq!(contract.get_account_info(&"alice.near".to_string()).reward, 20 + 1000 + 1000);
+
+        // A second migration of the current state changes nothing
+        env::state_write(&contract);
+        let contract = StakingContract::migrate(None);
+        assert_eq!(contract.version, CURRENT_VERSION);
+        assert_eq!(contract.total_stake, 1000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot downgrade the contract from version 4 to 3")]
+    fn test_migrate_refuses_downgrade() {
+        let context = get_context(false);
+        testing_env!(context.build());
+
+        let mut state = base64::decode(STATE_V2).unwrap();
+        let len = state.len();
+        state[len - 16] = 4;
+        env::storage_write(b"STATE", &state);
+
+        StakingContract::migrate(None);
+    }
+
+    #[test]
+    fn test_version1_account_keeps_its_reward() {
+        let context = get_context(false);
+        testing_env!(context.build());
+        write_fixture_account("alice.near", ACCOUNT_V1);
+
+        // The reward of version 1 is converted as is, not replaced by the stake
+        let accounts: LookupMap<AccountId, UpgradableAccount> = LookupMap::new(StorageKey::AccountKey);
+        let account = Account::from(accounts.get(&"alice.near".to_string()).unwrap());
+        assert_eq!(account.stake_balance, 500);
+        assert_eq!(account.pre_reward, 7);
+    }
+
+    #[test]
+    fn test_legacy_unstaking_joins_the_total_when_rewritten() {
+        let mut context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+        let legacy_account = AccountV2 {
+            stake_balance: 0,
+            pre_reward: 0,
+            last_block_balance_change: 0,
+            unstake_balance: 300,
+            unstake_start_time: 0,
+            unstake_available_epoch: 0,
+            membership: Membership::Basic,
+        };
+        contract.accounts.insert(&accounts(1).to_string(), &UpgradableAccount::Version2(legacy_account));
+
+        // Withdrawing before the migration does not underflow the total
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(1).build());
+        contract.withdraw();
+        assert_eq!(contract.total_unstaking, 0);
+        let report = contract.get_invariant_report(None, None);
+        assert_eq!(report.unstake_balance, report.total_unstaking);
+    }
+
+    #[test]
+    fn test_migrate_legacy_accounts() {
+        let mut context = get_context(false);
+        testing_env!(context.block_index(50).build());
+        let mut contract = StakingContract::new(accounts(0).to_string(), accounts(2).to_string(), Config::default());
+        write_fixture_account("alice.near", ACCOUNT_V1);
+        write_fixture_account("bob.near", ACCOUNT_V2);
+        // Pool values as migrated from version 2, where the accounts earn with their stake until they are rewritten
+        contract.total_stake = 500 + 1000;
+        contract.num_staker = 2;
+        contract.num_legacy_accounts = 2;
+        contract.legacy_stake = 500 + 1000;
+        contract.total_reward_weight = 500 + 1000;
+        contract.account_migration_completed = false;
+
+        let preview = contract.preview_account_migration(vec!["alice.near".to_string(), "carol.near".to_string()]);
+        assert_eq!(preview[0].stored_version, Some(1));
+        let account = preview[0].account.as_ref().unwrap();
+        assert_eq!(account.stake_balance, 500);
+        assert_eq!(account.reward, 7 + contract.config.reward_num as Balance * 500 * 10 / contract.config.reward_denom as Balance);
+        assert!(preview[1].account.is_none());
+        assert_eq!(contract.get_legacy_accounts(vec!["carol.near".to_string(), "bob.near".to_string()]), vec!["bob.near".to_string()]);
+
+        let account_ids = vec!["alice.near".to_string(), "bob.near".to_string(), "carol.near".to_string()];
+        assert_eq!(contract.migrate_accounts(account_ids), 3);
+        assert_eq!(contract.get_num_legacy_accounts(), 0);
+        assert_eq!(contract.get_legacy_stake().0, 0);
+        assert_eq!(contract.get_invariant_report(None, None).stake_balance.0, contract.total_stake);
+        assert_eq!(contract.accounts.get(&"alice.near".to_string()).unwrap().version(), 3);
+        assert_eq!(contract.accounts.get(&"bob.near".to_string()).unwrap().version(), 3);
+        // Rewritten accounts earn with their membership multiplier
+        assert_eq!(contract.total_reward_weight, 500 + 1100);
+
+        // Nothing left to migrate
+        assert!(contract.get_legacy_accounts(vec!["alice.near".to_string(), "bob.near".to_string()]).is_empty());
+        assert_eq!(contract.migrate_accounts(vec!["alice.near".to_string()]), 1);
+        assert_eq!(contract.get_num_legacy_accounts(), 0);
+        contract.complete_account_migration();
+        assert!(matches!(contract.check_invariants(None), PromiseOrValue::Promise(_)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Legacy accounts remain, complete the account migration first")]
+    fn test_no_solvency_check_before_the_account_migration() {
+        let context = get_context(false);
+        testing_env!(context.build());
+        write_fixture_state(STATE_V2);
+        write_fixture_account("alice.near", ACCOUNT_V2);
+
+        // The migrated pool sums match, but the legacy unstaking balances are unknown
+        let mut contract = StakingContract::migrate(None);
+        contract.owner_id = accounts(0).to_string();
+        contract.check_invariants(None);
+    }
+
+    #[test]
+    fn test_stage_and_deploy_code() {
+        let mut context = get_context(false);
+        let contract = setup_contract(&context, Config::default());
+        env::state_write(&contract);
+
+        let code = vec![0u8, 97, 115, 109];
+        context.context.input = code.clone();
+        testing_env!(context.build());
+        let mut contract: StakingContract = env::state_read().unwrap();
+        let code_hash = contract.stage_code();
+        assert_eq!(CryptoHash::from(code_hash).to_vec(), env::sha256(&code));
+        assert_eq!(env::storage_read(STAGED_CODE_KEY), Some(code));
+
+        context.context.input = vec![];
+        testing_env!(context.block_timestamp(DEFAULT_ACTION_DELAY).build());
+        contract.deploy_staged_code();
+        env::state_write(&contract);
+
+        // Only the migration of the staged code records the upgrade
+        let contract = StakingContract::migrate(None);
+        assert!(contract.get_upgrade_history(None, None).is_empty());
+        assert!(contract.get_staged_code().is_some());
+        let contract = StakingContract::migrate(Some(CryptoHash::default().into()));
+        assert!(contract.get_upgrade_history(None, None).is_empty());
+
+        let contract = StakingContract::migrate(Some(code_hash));
+        let history = contract.get_upgrade_history(None, None);
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].code_hash, code_hash);
+        assert_eq!(history[0].code_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(history[0].previous_version.0, CURRENT_VERSION);
+        assert!(contract.get_staged_code().is_none());
+        assert!(env::storage_read(STAGED_CODE_KEY).is_none());
+    }
+
+    #[test]
+    fn test_invariant_report_pages() {
+        let mut context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+
+        for (user, amount) in [(accounts(1), 1000), (accounts(3), 0), (accounts(4), 500)] {
+            register(&mut contract, &mut context, user.clone());
+            if amount > 0 {
+                stake(&mut contract, &mut context, user, amount);
+            }
+        }
+        testing_env!(context.predecessor_account_id(accounts(4)).attached_deposit(1).build());
+        contract.unstake(U128(200));
+
+        let first_page = contract.get_invariant_report(None, Some(2));
+        let second_page = contract.get_invariant_report(Some(2), Some(2));
+        assert_eq!(first_page.num_accounts, 3);
+        assert_eq!(first_page.num_scanned + second_page.num_scanned, 3);
+        assert_eq!(first_page.stake_balance.0 + second_page.stake_balance.0, first_page.total_stake.0);
+        assert_eq!((first_page.num_stakers + second_page.num_stakers) as u128, first_page.num_staker.0);
+        assert_eq!(first_page.unstake_balance.0 + second_page.unstake_balance.0, 200);
+
+        // The owner scan keeps its progress between calls
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(0).build());
+        assert!(matches!(contract.check_invariants(Some(2)), PromiseOrValue::Value(_)));
+        assert_eq!(contract.invariant_scan.as_ref().unwrap().next_index, 2);
+        assert!(matches!(contract.check_invariants(Some(2)), PromiseOrValue::Promise(_)));
+        assert!(contract.invariant_scan.is_none());
+    }
+
+    #[test]
+    fn test_invariant_scan_follows_account_changes() {
+        let mut context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+
+        for (user, amount) in [(accounts(1), 1000), (accounts(3), 0), (accounts(4), 500)] {
+            register(&mut contract, &mut context, user.clone());
+            if amount > 0 {
+                stake(&mut contract, &mut context, user, amount);
+            }
+        }
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(0).build());
+        assert!(matches!(contract.check_invariants(Some(2)), PromiseOrValue::Value(_)));
+
+        // A scanned account stakes more, another one closes and the last account takes its place
+        stake(&mut contract, &mut context, accounts(1), 300);
+        testing_env!(context.predecessor_account_id(accounts(3)).attached_deposit(1).build());
+        contract.close_account();
+        assert_eq!(contract.account_ids.len(), 2);
+        assert_eq!(contract.account_ids.get(1), Some(accounts(4).to_string()));
+
+        // The scan goes on with the adjusted sums instead of restarting
+        let scan = contract.invariant_scan.as_ref().unwrap();
+        assert_eq!((scan.next_index, scan.totals.stake_balance, scan.totals.num_stakers), (2, 1800, 2));
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(0).build());
+        assert!(matches!(contract.check_invariants(Some(2)), PromiseOrValue::Promise(_)));
+        assert!(contract.invariant_scan.is_none());
+    }
+
+    #[test]
+    fn test_reward_without_overflow() {
+        let mut context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+
+        // 1M tokens with 24 decimals, idle for 1B blocks: the product does not fit in `u128`
+        let stake = 10u128.pow(30);
+        register_and_stake(&mut contract, &mut context, accounts(1), stake);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(1).block_index(1_000_000_000).build());
+        let expected_reward = 715 * stake;
+        assert_eq!(contract.get_account_info(&accounts(1).to_string()).reward, expected_reward);
+        contract.unstake(U128(stake));
+        assert_eq!(contract.get_account_info(&accounts(1).to_string()).reward, expected_reward);
+        assert_eq!(contract.get_pool_info().total_reward.0, expected_reward);
+    }
+
+    #[test]
+    fn test_reward_projection_views() {
+        let mut context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+        assert_eq!(contract.get_current_apr(), (715 * 10_000 * 31_536_000u64 / 1_000_000_000) as u32);
+
+        register_and_stake(&mut contract, &mut context, accounts(1), 10u128.pow(24));
+        assert_eq!(contract.get_reward_rate_per_block().pool_reward_per_block.0, 715 * 10u128.pow(15));
+
+        // The projection matches the reward accrued once the blocks are produced
+        let estimate = contract.estimate_reward(Some(accounts(1).to_string()), None, Some(1_000), None);
+        assert_eq!(estimate, contract.estimate_reward(None, Some(U128(10u128.pow(24))), Some(1_000), None));
+        testing_env!(context.block_index(1_000).build());
+        assert_eq!(contract.get_account_info(&accounts(1).to_string()).reward, estimate.0);
+
+        // Nothing accrues while paused
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.pause();
+        assert_eq!(contract.estimate_reward(Some(accounts(1).to_string()), None, None, Some(10)).0, 0);
+        assert_eq!(contract.get_current_apr(), 0);
+    }
+
+    #[test]
+    fn test_reward_projection_views_of_a_membership_tier() {
+        let mut context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+
+        register_and_stake(&mut contract, &mut context, accounts(1), 10u128.pow(24));
+        let mut account = Account::from(contract.accounts.get(&accounts(1).to_string()).unwrap());
+        account.membership = Membership::Companion;
+        contract.internal_save_account(&accounts(1).to_string(), account);
+
+        // The tier multiplies the reward of the same stake, in every view
+        let basic_estimate = contract.estimate_reward(None, Some(U128(10u128.pow(24))), Some(1_000), None).0;
+        let estimate = contract.estimate_reward(Some(accounts(1).to_string()), None, Some(1_000), None).0;
+        assert_eq!(estimate, basic_estimate * 12 / 10);
+        assert_eq!(contract.get_reward_rate_per_block().pool_reward_per_block.0, 715 * 10u128.pow(15) * 12 / 10);
+        testing_env!(context.block_index(1_000).build());
+        assert_eq!(contract.get_account_info(&accounts(1).to_string()).reward, estimate);
+        assert_eq!(contract.get_pool_info().total_reward.0, estimate);
+
+        // Nothing accrues while paused, whatever the tier
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.pause();
+        testing_env!(context.block_index(2_000).build());
+        assert_eq!(contract.get_account_info(&accounts(1).to_string()).reward, estimate);
+        assert_eq!(contract.get_reward_rate_per_block().pool_reward_per_block.0, 0);
+        assert!(contract.get_reward_rate_per_block().is_paused);
+        assert_eq!(contract.estimate_reward(Some(accounts(1).to_string()), None, Some(1_000), None).0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Account has a pending Harvest operation")]
+    fn test_pending_harvest_locks_account() {
+        let mut context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+
+        register_and_stake(&mut contract, &mut context, accounts(1), 10u128.pow(24));
+
+        // The second harvest of the same block cannot pay the reward again
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(1).block_index(100).build());
+        contract.harvest();
+        assert_eq!(contract.get_pending_operation(accounts(1).to_string()), Some(PendingOperation::Harvest));
+        contract.harvest();
+    }
+
+    #[test]
+    #[should_panic(expected = "Storage pool limit of the account is reached")]
+    fn test_auto_register_limit_per_account() {
+        let mut context = get_context(false);
+        let config = Config {
+            auto_register: Some(AutoRegisterConfig { max_storage_per_account: U128(1) }),
+            ..Config::default()
+        };
+        let mut contract = setup_contract(&context, config);
+        testing_env!(context.attached_deposit(10u128.pow(24)).build());
+        contract.fund_storage_pool();
+
+        stake(&mut contract, &mut context, accounts(1), 1000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Deposit of 50 is refused because of the stake limits")]
+    fn test_auto_register_refused_deposit() {
+        let mut context = get_context(false);
+        let config = Config {
+            auto_register: Some(AutoRegisterConfig { max_storage_per_account: U128(10u128.pow(24)) }),
+            min_stake_amount: Some(U128(100)),
+            ..Config::default()
+        };
+        let mut contract = setup_contract(&context, config);
+        testing_env!(context.attached_deposit(10u128.pow(24)).build());
+        contract.fund_storage_pool();
+
+        // The deposit is below the minimum stake, so the storage pool does not pay for a new account
+        stake(&mut contract, &mut context, accounts(1), 50);
+    }
+
+    #[test]
+    #[should_panic(expected = "Beneficiary not found, and the prepaid storage of the sender cannot register it")]
+    fn test_storage_pool_does_not_register_beneficiaries() {
+        let mut context = get_context(false);
+        let config = Config {
+            auto_register: Some(AutoRegisterConfig { max_storage_per_account: U128(10u128.pow(24)) }),
+            ..Config::default()
+        };
+        let mut contract = setup_contract(&context, config);
+        testing_env!(context.attached_deposit(10u128.pow(24)).build());
+        contract.fund_storage_pool();
+
+        testing_env!(context.predecessor_account_id(accounts(2)).attached_deposit(0).build());
+        let msg = format!("{{\"beneficiary_id\":\"{}\"}}", accounts(3));
+        contract.ft_on_transfer(accounts(1).to_string(), U128(1), msg);
+    }
+
+    #[test]
+    fn test_transfer_stake() {
+        let mut context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+
+        register(&mut contract, &mut context, accounts(1));
+        register(&mut contract, &mut context, accounts(3));
+        stake(&mut contract, &mut context, accounts(1), 10u128.pow(24));
+
+        // The reward earned before the transfer stays with the sender
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(1).block_index(100).build());
+        contract.transfer_stake(accounts(3).to_string(), U128(10u128.pow(24)), None);
+        let sender = contract.get_account_info(&accounts(1).to_string());
+        let receiver = contract.get_account_info(&accounts(3).to_string());
+        assert_eq!(sender.stake_balance, 0);
+        assert_eq!(sender.reward, 715 * 10u128.pow(17));
+        assert_eq!(receiver.stake_balance, 10u128.pow(24));
+        assert_eq!(receiver.reward, 0);
+        assert_eq!(contract.num_staker, 1);
+        assert_eq!(contract.total_stake, 10u128.pow(24));
+
+        testing_env!(context.block_index(200).build());
+        assert_eq!(contract.get_account_info(&accounts(1).to_string()).reward, 715 * 10u128.pow(17));
+        assert_eq!(contract.get_account_info(&accounts(3).to_string()).reward, 715 * 10u128.pow(17));
+    }
+
+    #[test]
+    fn test_receipt_token_follows_stake() {
+        let mut context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+
+        register(&mut contract, &mut context, accounts(1));
+        register(&mut contract, &mut context, accounts(3));
+        stake(&mut contract, &mut context, accounts(1), 1000);
+        assert!(get_logs().iter().any(|log| log.starts_with("EVENT_JSON:") && log.contains("\"ft_mint\"")));
+        assert_eq!(contract.ft_balance_of(accounts(1).to_string()).0, 1000);
+        assert_eq!(contract.ft_total_supply().0, 1000);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(1).build());
+        contract.ft_transfer(accounts(3).to_string(), U128(400), None);
+        contract.unstake(U128(100));
+        assert!(get_logs().iter().any(|log| log.contains("\"ft_burn\"")));
+        assert_eq!(contract.ft_balance_of(accounts(1).to_string()).0, 500);
+        assert_eq!(contract.ft_balance_of(accounts(3).to_string()).0, 400);
+        assert_eq!(contract.ft_balance_of(accounts(4).to_string()).0, 0);
+        assert_eq!(contract.ft_total_supply().0, 900);
+    }
+
+    fn setup_locked_position(context: &mut VMContextBuilder) -> StakingContract {
+        let config = Config {
+            position: Some(PositionConfig { min_lock_epochs: 1, max_lock_epochs: 10, max_boost_bps: 5_000 }),
+            ..Config::default()
+        };
+        let mut contract = setup_contract(context, config);
+        register(&mut contract, context, accounts(1));
+        register(&mut contract, context, accounts(3));
+        stake(&mut contract, context, accounts(1), 10u128.pow(24));
+
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(10u128.pow(24)).build());
+        assert_eq!(contract.lock_stake(U128(10u128.pow(24)), 10), "0");
+        contract
+    }
+
+    #[test]
+    fn test_locked_position_token() {
+        let mut context = get_context(false);
+        testing_env!(context.build());
+        let mut contract = setup_locked_position(&mut context);
+        assert!(get_logs().iter().any(|log| log.contains("\"nep171\"") && log.contains("\"nft_mint\"")));
+        assert_eq!(contract.get_total_boost().0, 5 * 10u128.pow(23));
+
+        // The boost of 50% earns reward as well
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(1).block_index(100).build());
+        assert_eq!(contract.get_account_info(&accounts(1).to_string()).reward, 10725 * 10u128.pow(16));
+
+        // The stake, the lock and the boost move with the token, the earned reward stays
+        contract.nft_transfer(accounts(3).to_string(), "0".to_string(), None, None);
+        let sender = contract.get_account_info(&accounts(1).to_string());
+        let receiver = contract.get_account_info(&accounts(3).to_string());
+        assert_eq!((sender.stake_balance, sender.locked_balance, sender.boost_balance), (0, 0, 0));
+        assert_eq!(sender.reward, 10725 * 10u128.pow(16));
+        assert_eq!(receiver.stake_balance, 10u128.pow(24));
+        assert_eq!(receiver.locked_balance, 10u128.pow(24));
+        assert_eq!(receiver.boost_balance, 5 * 10u128.pow(23));
+        assert_eq!(contract.nft_supply_for_owner(accounts(1).to_string()).0, 0);
+
+        let tokens = contract.nft_tokens_for_owner(accounts(3).to_string(), None, None);
+        assert_eq!(tokens.len(), 1);
+        let extra = tokens[0].metadata.extra.clone().unwrap();
+        assert!(extra.contains("\"lock_end_epoch\":10") && extra.contains("\"boost_bps\":5000"));
+        assert_eq!(contract.nft_metadata().spec, "nft-1.0.0");
+
+        // Once expired, the position becomes a plain stake
+        testing_env!(context.predecessor_account_id(accounts(3)).epoch_height(10).build());
+        contract.unlock_position("0".to_string());
+        let account = contract.get_account_info(&accounts(3).to_string());
+        assert_eq!((account.locked_balance, account.boost_balance), (0, 0));
+        assert_eq!(contract.nft_total_supply().0, 0);
+        assert_eq!(contract.get_total_boost().0, 0);
+        contract.unstake(U128(10u128.pow(24)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot unstake the stake of locked positions")]
+    fn test_locked_position_cannot_unstake() {
+        let mut context = get_context(false);
+        testing_env!(context.build());
+        let mut contract = setup_locked_position(&mut context);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(1).build());
+        contract.unstake(U128(1));
+    }
+
+    #[test]
+    fn test_emergency_withdraw_keeps_locked_positions() {
+        let mut context = get_context(false);
+        testing_env!(context.build());
+        let mut contract = setup_locked_position(&mut context);
+        stake(&mut contract, &mut context, accounts(1), 300);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).block_index(100).build());
+        contract.pause();
+
+        // Only the unlocked stake is withdrawn, the position keeps its stake and its boost
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(1).block_index(200).build());
+        contract.emergency_withdraw();
+        let account = contract.get_account_info(&accounts(1).to_string());
+        assert_eq!((account.stake_balance, account.locked_balance, account.boost_balance), (10u128.pow(24), 10u128.pow(24), 5 * 10u128.pow(23)));
+        // The position earns from the last settlement of the pool, which was its lock, until the pause
+        assert_eq!(account.reward, 10725 * 10u128.pow(16));
+        assert_eq!((contract.total_stake, contract.num_staker), (10u128.pow(24), 1));
+        assert_eq!(contract.get_total_boost().0, 5 * 10u128.pow(23));
+        assert_eq!(contract.nft_supply_for_owner(accounts(1).to_string()).0, 1);
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).attached_deposit(0).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])]
+        );
+        let withdrawn = Account::from(contract.accounts.get(&accounts(1).to_string()).unwrap());
+        let withdrawn = Account { stake_balance: 300, locked_balance: 0, boost_balance: 0, ..withdrawn };
+        assert_eq!(contract.ft_emergency_withdraw_callback(accounts(1).to_string(), withdrawn).0, 300);
+        assert_eq!(contract.nft_supply_for_owner(accounts(1).to_string()).0, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Nothing to withdraw")]
+    fn test_emergency_withdraw_of_locked_stake_only() {
+        let mut context = get_context(false);
+        testing_env!(context.build());
+        let mut contract = setup_locked_position(&mut context);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(0).build());
+        contract.pause();
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(1).build());
+        contract.emergency_withdraw();
+    }
+
+    #[test]
+    fn test_batch_register_and_airdrop_stake() {
+        let mut context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        let msg = r#"{"action":"fund_airdrop"}"#.to_string();
+        contract.ft_on_transfer(accounts(0).to_string(), U128(1000), msg);
+        assert_eq!(contract.get_airdrop_balance().0, 1000);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(10u128.pow(24)).build());
+        let account_ids = vec![accounts(1).to_string(), accounts(3).to_string()];
+        assert_eq!(contract.batch_register(account_ids, None), 2);
+        assert!(contract.exist_account(accounts(3).to_string()));
+
+        // The unregistered account is skipped, and the list can be resumed from any index
+        testing_env!(context.attached_deposit(0).build());
+        let credit = |account_id: AccountId, amount: Balance| AirdropCredit { account_id, amount: U128(amount) };
+        let credits = vec![credit(accounts(1).to_string(), 300), credit(accounts(4).to_string(), 100), credit(accounts(3).to_string(), 200)];
+        assert_eq!(contract.airdrop_stake(credits, Some(1)), 3);
+        assert!(get_logs().iter().any(|log| log.contains("is skipped")));
+        assert_eq!(contract.get_account_info(&accounts(1).to_string()).stake_balance, 0);
+        assert_eq!(contract.get_account_info(&accounts(3).to_string()).stake_balance, 200);
+        assert_eq!(contract.get_airdrop_balance().0, 800);
+        assert_eq!((contract.total_stake, contract.num_staker), (200, 1));
+    }
+
+    #[test]
+    fn test_withdraw_airdrop_balance() {
+        let mut context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+        contract.grant_role(Role::TreasuryManager, accounts(4).to_string());
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        let msg = r#"{"action":"fund_airdrop"}"#.to_string();
+        contract.ft_on_transfer(accounts(0).to_string(), U128(1000), msg);
+
+        testing_env!(context.predecessor_account_id(accounts(4)).attached_deposit(1).build());
+        contract.withdraw_airdrop_balance(U128(600), accounts(4).to_string());
+        assert_eq!(contract.get_airdrop_balance().0, 400);
+        assert!(get_logs().iter().any(|log| log.contains("\"airdrop_withdrawn\"")));
+
+        // A failed transfer restores the balance
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        assert_eq!(contract.ft_airdrop_withdraw_callback(U128(600)).0, 0);
+        assert_eq!(contract.get_airdrop_balance().0, 1000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot withdraw more than the airdrop balance 1000")]
+    fn test_withdraw_more_than_the_airdrop_balance() {
+        let mut context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        let msg = r#"{"action":"fund_airdrop"}"#.to_string();
+        contract.ft_on_transfer(accounts(0).to_string(), U128(1000), msg);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(1).build());
+        contract.withdraw_airdrop_balance(U128(1001), accounts(0).to_string());
+    }
+
+    #[test]
+    fn test_batch_register_refunds_the_payer() {
+        let mut context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+        contract.grant_role(Role::TreasuryManager, accounts(4).to_string());
+
+        testing_env!(context.predecessor_account_id(accounts(4)).attached_deposit(10u128.pow(24)).build());
+        assert_eq!(contract.batch_register(vec![accounts(1).to_string()], None), 1);
+        let storage_charge = contract.storage_charges.get(&accounts(1).to_string()).unwrap();
+        assert_eq!(storage_charge.payer, StoragePayer::Account(accounts(4).to_string()));
+
+        // The storage goes back to the treasury manager which paid it, not to the account
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(1).build());
+        contract.close_account();
+        let receipts = near_sdk::test_utils::get_created_receipts();
+        let receipt = format!("{:?}", receipts.last().unwrap());
+        assert!(receipt.contains(&format!("receiver_id: {:?}", accounts(4).to_string())));
+        assert!(receipt.contains(&format!("deposit: {}", storage_charge.amount.0)));
+        assert!(contract.storage_charges.get(&accounts(1).to_string()).is_none());
+    }
+
+    #[test]
+    fn test_close_account_of_referrer() {
+        let mut context = get_context(false);
+        let config = Config { referral_reward_bps: 1_000, ..Config::default() };
+        let mut contract = setup_contract(&context, config);
+
+        register(&mut contract, &mut context, accounts(1));
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.storage_deposit(None, None, Some(accounts(1).to_string()));
+        stake(&mut contract, &mut context, accounts(3), 10u128.pow(24));
+
+        // Without funds, the account is closed at once
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(1).build());
+        assert!(matches!(contract.close_account(), PromiseOrValue::Value(U128(0))));
+        assert!(!contract.exist_account(accounts(1).to_string()));
+        assert!(get_logs().iter().any(|log| log.contains("\"account_closed\"")));
+        assert_eq!(contract.account_ids.len(), 1);
+        assert!(contract.referees.get(&accounts(1).to_string()).is_none());
+        assert!(Account::from(contract.accounts.get(&accounts(3).to_string()).unwrap()).referrer_id.is_none());
+
+        // The referee keeps earning, its referrer share is not paid anymore
+        testing_env!(context.predecessor_account_id(accounts(3)).block_index(100).build());
+        contract.unstake(U128(10u128.pow(24)));
+        assert_eq!(contract.get_account_info(&accounts(3).to_string()).reward, 715 * 10u128.pow(17));
+    }
+
+    #[test]
+    fn test_close_account_in_vesting_mode_completes() {
+        let mut context = get_context(false);
+        let config = Config {
+            vesting: Some(VestingConfig { cliff_epochs: 2, duration_epochs: 4 }),
+            ..Config::default()
+        };
+        let mut contract = setup_contract(&context, config);
+
+        register_and_stake(&mut contract, &mut context, accounts(1), 10u128.pow(24));
+
+        // The first call unstakes and vests the reward until epoch 4
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(1).block_index(100).build());
+        assert!(matches!(contract.close_account(), PromiseOrValue::Value(U128(0))));
+        assert_eq!(contract.get_vesting_info(accounts(1).to_string()).unwrap().end_epoch, Some(4));
+
+        // A reward credited meanwhile vests until the same end, so the close is not delayed again
+        contract.internal_pay_referral_reward(&accounts(1).to_string(), 1000);
+        testing_env!(context.epoch_height(4).build());
+        assert!(matches!(contract.close_account(), PromiseOrValue::Promise(_)));
+        assert!(!contract.exist_account(accounts(1).to_string()));
+        assert!(contract.get_vesting_info(accounts(1).to_string()).is_none());
+    }
+
+    #[test]
+    fn test_close_refunds_the_storage_pool_once() {
+        let mut context = get_context(false);
+        let config = Config {
+            auto_register: Some(AutoRegisterConfig { max_storage_per_account: U128(10u128.pow(24)) }),
+            ..Config::default()
+        };
+        let mut contract = setup_contract(&context, config);
+        testing_env!(context.attached_deposit(10u128.pow(24)).build());
+        contract.fund_storage_pool();
+
+        stake(&mut contract, &mut context, accounts(1), 1000);
+        let storage_charge = contract.storage_charges.get(&accounts(1).to_string()).unwrap();
+        assert_eq!(storage_charge.payer, StoragePayer::StoragePool);
+        assert_eq!(contract.storage_pool, 10u128.pow(24) - storage_charge.amount.0);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(1).build());
+        contract.close_account();
+        let old_account = Account::from(contract.accounts.get(&accounts(1).to_string()).unwrap());
+        testing_env!(context.epoch_height(1).build());
+        assert!(matches!(contract.close_account(), PromiseOrValue::Promise(_)));
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])]
+        );
+        contract.ft_close_account_callback(accounts(1).to_string(), old_account, U128(0), storage_charge);
+        assert_eq!(contract.storage_pool, 10u128.pow(24));
+        assert_eq!(contract.get_storage_pool_usage(accounts(1).to_string()).0, 0);
+        assert_eq!(contract.pending_storage_refunds, 0);
+
+        // Registered again with its own deposit, the account gets its storage back instead of the pool
+        register(&mut contract, &mut context, accounts(1));
+        let storage_charge = contract.storage_charges.get(&accounts(1).to_string()).unwrap();
+        testing_env!(context.attached_deposit(1).build());
+        contract.close_account();
+        let receipt = format!("{:?}", near_sdk::test_utils::get_created_receipts().last().unwrap());
+        assert!(receipt.contains(&format!("receiver_id: {:?}", accounts(1).to_string())));
+        assert!(receipt.contains(&format!("deposit: {}", storage_charge.amount.0)));
+        assert_eq!(contract.storage_pool, 10u128.pow(24));
+    }
+
+    #[test]
+    fn test_failed_close_does_not_overwrite_a_new_registration() {
+        let mut context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+
+        register(&mut contract, &mut context, accounts(1));
+        let storage_charge = contract.storage_charges.get(&accounts(1).to_string()).unwrap();
+        stake(&mut contract, &mut context, accounts(1), 1000);
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(1).build());
+        contract.close_account();
+        let old_account = Account::from(contract.accounts.get(&accounts(1).to_string()).unwrap());
+        testing_env!(context.epoch_height(1).build());
+        assert!(matches!(contract.close_account(), PromiseOrValue::Promise(_)));
+
+        // The pending operation is cleared while the transfer is in flight, and the account registers again
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(0).build());
+        contract.clear_pending_operation(accounts(1).to_string());
+        register_and_stake(&mut contract, &mut context, accounts(1), 500);
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        contract.ft_close_account_callback(accounts(1).to_string(), old_account, U128(0), storage_charge.clone());
+        let account = contract.get_account_info(&accounts(1).to_string());
+        assert_eq!((account.stake_balance, account.unstake_balance), (500, 1000));
+        assert_eq!(contract.total_unstaking, 1000);
+        assert_eq!(contract.storage_charges.get(&accounts(1).to_string()).unwrap().payer, StoragePayer::Account(accounts(1).to_string()));
+        let receipt = format!("{:?}", near_sdk::test_utils::get_created_receipts().last().unwrap());
+        assert!(receipt.contains(&format!("deposit: {}", storage_charge.amount.0)));
+    }
+
+    #[test]
+    fn test_rescue_near_over_liabilities() {
+        let mut context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(5 * 10u128.pow(24)).build());
+        contract.prepay_storage();
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(2 * 10u128.pow(24)).build());
+        contract.fund_storage_pool();
+        contract.grant_role(Role::TreasuryManager, accounts(4).to_string());
+
+        // A closing account frees its storage before its transfer is resolved
+        register_and_stake(&mut contract, &mut context, accounts(3), 1000);
+        testing_env!(context.predecessor_account_id(accounts(3)).attached_deposit(1).build());
+        contract.close_account();
+        testing_env!(context.predecessor_account_id(accounts(3)).attached_deposit(1).epoch_height(1).build());
+        assert!(matches!(contract.close_account(), PromiseOrValue::Promise(_)));
+        let pending_storage_refunds = contract.pending_storage_refunds;
+        assert!(pending_storage_refunds > 0);
+
+        // The prepaid storage, the storage pool, the pending storage refunds and the storage of the state are not rescuable
+        testing_env!(context.predecessor_account_id(accounts(4)).attached_deposit(1).account_balance(100 * 10u128.pow(24)).build());
+        let storage_cost = env::storage_byte_cost() * Balance::from(env::storage_usage());
+        let rescuable = 93 * 10u128.pow(24) + 1 - storage_cost - pending_storage_refunds;     // The balance includes the attached yocto
+        assert_eq!(contract.get_rescuable_near().0, rescuable);
+        contract.rescue_near(accounts(0).to_string(), U128(rescuable));
+        assert!(get_logs().iter().any(|log| log.contains("\"near_rescued\"")));
+    }
+
+    #[test]
+    fn test_rescue_staked_token_surplus() {
+        let mut context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+
+        register_and_stake(&mut contract, &mut context, accounts(1), 1000);
+
+        // 500 tokens were sent by a plain transfer, over the stake of 1000
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(1).build());
+        contract.rescue_ft(accounts(2).to_string(), accounts(0).to_string(), U128(500));
+        testing_env!(
+            context.attached_deposit(0).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Successful(b"\"1500\"".to_vec())]
+        );
+        contract.ft_rescue_surplus_callback(accounts(0).to_string(), U128(500));
+        assert_eq!(contract.pending_transfers, 500);
+        assert!(get_logs().iter().any(|log| log.contains("\"ft_rescued\"")));
+
+        testing_env!(
+            context.build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])]
+        );
+        assert_eq!(contract.ft_rescue_callback(U128(500)).0, 500);
+        assert_eq!(contract.pending_transfers, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot rescue more than the surplus 400 of the staked token")]
+    fn test_rescue_staked_token_over_liabilities() {
+        let mut context = get_context(false);
+        let mut contract = setup_contract(&context, Config::default());
+
+        register_and_stake(&mut contract, &mut context, accounts(1), 1000);
+        let msg = r#"{"action":"fund_airdrop"}"#.to_string();
+        contract.ft_on_transfer(accounts(0).to_string(), U128(100), msg);
+
+        // The airdrop balance is owed as well
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Successful(b"\"1500\"".to_vec())]
+        );
+        contract.ft_rescue_surplus_callback(accounts(0).to_string(), U128(500));
+    }
+
+    // Realistic bounds: a supply of 1T tokens with 24 decimals, 100M blocks (about 3 years),
+    // and a reward rate up to 1e-6 per block (about 3000% per year), which includes the default rate
+    const MAX_SUPPLY: Balance = 10u128.pow(36);
+    const MAX_BLOCKS: BlockHeight = 100_000_000;
+
+    fn realistic_rate() -> impl Strategy<Value = (u32, u64)> {
+        let config = Config::default();
+        prop_oneof![
+            Just((config.reward_num, config.reward_denom)),
+            (1..=u32::MAX, 0..u64::MAX / 2).prop_map(|(reward_num, extra)| {
+                (reward_num, reward_num as u64 * 1_000_000 + extra)
+            }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn prop_reward_does_not_overflow(
+            (reward_num, reward_denom) in realistic_rate(),
+            stake in 0..=MAX_SUPPLY,
+            cnt_block in 0..=MAX_BLOCKS,
+            remainder in any::<u64>(),
+        ) {
+            let remainder = remainder % reward_denom;
+            let (reward_per_share, new_remainder) = reward_per_share_growth(reward_num, reward_denom, cnt_block, remainder);
+            prop_assert!(new_remainder < reward_denom);
+            let (reward, new_remainder) = calculate_reward(stake, reward_per_share, REWARD_PER_SHARE_SCALE - 1);
+            prop_assert!(new_remainder < REWARD_PER_SHARE_SCALE);
+            prop_assert!(reward <= mul_div(stake, cnt_block as Balance, 1_000_000) + 1);
+        }
+
+        #[test]
+        fn prop_reward_saturates(stake in any::<u128>(), reward_per_share in any::<u128>()) {
+            let exact = U256::from(stake) * U256::from(reward_per_share) / U256::from(REWARD_PER_SHARE_SCALE);
+            let (reward, _) = calculate_reward(stake, reward_per_share, 0);
+            prop_assert_eq!(reward, exact.min(U256::from(u128::MAX)).as_u128());
+        }
+
+        #[test]
+        fn prop_reward_remainder_is_not_lost(
+            (reward_num, reward_denom) in realistic_rate(),
+            stake in 0..=MAX_SUPPLY,
+            cnt_block in 0..=MAX_BLOCKS,
+            split in 0..=MAX_BLOCKS,
+        ) {
+            // Settling in two steps gives the same reward per share, and the same reward, as settling once
+            let split = split % (cnt_block + 1);
+            let (first_growth, remainder) = reward_per_share_growth(reward_num, reward_denom, split, 0);
+            let (second_growth, remainder) = reward_per_share_growth(reward_num, reward_denom, cnt_block - split, remainder);
+            let (growth, expected_remainder) = reward_per_share_growth(reward_num, reward_denom, cnt_block, 0);
+            prop_assert_eq!(first_growth + second_growth, growth);
+            prop_assert_eq!(remainder, expected_remainder);
+
+            let (first_reward, remainder) = calculate_reward(stake, first_growth, 0);
+            let (second_reward, remainder) = calculate_reward(stake, second_growth, remainder);
+            let (reward, expected_remainder) = calculate_reward(stake, growth, 0);
+            prop_assert_eq!(first_reward + second_reward, reward);
+            prop_assert_eq!(remainder, expected_remainder);
+        }
+
+        #[test]
+        fn prop_mul_div_matches_u128(a in any::<u64>(), b in any::<u64>(), denom in 1..=u128::MAX) {
+            prop_assert_eq!(mul_div(a as u128, b as u128, denom), (a as u128) * (b as u128) / denom);
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use crate::*;
+use near_sdk::serde_json::json;
+
+// Version of the `StakingContract` layout
+// When the layout changes, snapshot the old one (like `StakingContractV2`),
+// add it to `VersionedContract` and bump this number
+pub const CURRENT_VERSION: u128 = 3;
+
+// Gas kept aside to finish `migrate_accounts` after the last account
+pub const MIGRATE_ACCOUNTS_GAS_RESERVE: Gas = 10_000_000_000_000;
+
+// Before version 3, the reward was computed from the blocks since the last change, at the current rate
+// Accounts written by those versions earn it until the migration, then follow the reward per share
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct LegacyRewardRate {
+    pub reward_num: u32,
+    pub reward_denom: u64,
+    pub until_block: BlockHeight,                   // Reward block of the migration, where `reward_per_share` starts
+}
+
+impl LegacyRewardRate {
+    // Reward of `stake` from the reward block `last_block` until the migration
+    pub fn reward_since(&self, stake: Balance, last_block: BlockHeight) -> Balance {
+        let cnt_block = self.until_block.saturating_sub(last_block);
+        let (reward_per_share, _) = reward_per_share_growth(self.reward_num, self.reward_denom, cnt_block, 0);
+        calculate_reward(stake, reward_per_share, 0).0
+    }
+}
+
+// Every known state layout
+// Version 1 has no `version` field, later versions keep it as the last field
+#[allow(clippy::large_enum_variant)]
+pub enum VersionedContract {
+    V1(StakingContractV1),
+    V2(StakingContractV2),
+    Current(StakingContract),
+}
+
+impl VersionedContract {
+    pub fn read(state: &[u8]) -> Self {
+        // Version 1 is the only layout without a trailing version
+        if let Ok(contract) = StakingContractV1::try_from_slice(state) {
+            return VersionedContract::V1(contract);
+        }
+
+        assert!(state.len() >= 16, "Unknown contract state");
+        let mut version = [0u8; 16];
+        version.copy_from_slice(&state[state.len() - 16..]);
+        let version = u128::from_le_bytes(version);
+        assert!(version <= CURRENT_VERSION, "Cannot downgrade the contract from version {} to {}", version, CURRENT_VERSION);
+
+        match version {
+            2 => VersionedContract::V2(StakingContractV2::try_from_slice(state).expect("Cannot read contract version 2")),
+            CURRENT_VERSION => VersionedContract::Current(StakingContract::try_from_slice(state).expect("Cannot read current contract")),
+            _ => env::panic(format!("Unknown contract version {}", version).as_bytes()),
+        }
+    }
+
+    pub fn version(&self) -> u128 {
+        match self {
+            VersionedContract::V1(_) => 1,
+            VersionedContract::V2(_) => 2,
+            VersionedContract::Current(_) => CURRENT_VERSION,
+        }
+    }
+
+    // Move the state forward by exactly one version
+    pub fn migrate_once(self) -> Self {
+        match self {
+            VersionedContract::V1(contract) => VersionedContract::V2(StakingContractV2::from(contract)),
+            VersionedContract::V2(contract) => VersionedContract::Current(StakingContract::from(contract)),
+            VersionedContract::Current(contract) => VersionedContract::Current(contract),
+        }
+    }
+
+    pub fn migrate(self) -> StakingContract {
+        let mut contract = self;
+        loop {
+            match contract {
+                VersionedContract::Current(contract) => return contract,
+                _ => {
+                    let from_version = contract.version();
+                    contract = contract.migrate_once();
+                    env::log(format!("Migrated contract state from version {} to {}", from_version, contract.version()).as_bytes());
+                },
+            }
+        }
+    }
+}
+
+impl From<StakingContractV1> for StakingContractV2 {
+    fn from(contract: StakingContractV1) -> Self {
+        StakingContractV2 {
+            owner_id: contract.owner_id,
+            ft_contract_id: contract.ft_contract_id,
+            config: contract.config,
+            total_stake: contract.total_stake,
+            total_paid_reward: contract.total_paid_reward,
+            num_staker: contract.num_staker,
+            pre_reward: contract.pre_reward,
+            last_block_balance_change: contract.last_block_balance_change,
+            accounts: contract.accounts,
+            paused: contract.paused,
+            paused_block: contract.paused_block,
+            version: 2,
+        }
+    }
+}
+
+// Accounts are converted when they are read, and rewritten lazily or by `migrate_accounts`
+impl From<StakingContractV2> for StakingContract {
+    fn from(contract: StakingContractV2) -> Self {
+        // The pool reward is settled with the old formula, the reward per share starts from there
+        let reward_block = if contract.paused {
+            contract.paused_block
+        } else {
+            env::block_index()
+        };
+        let legacy_reward_rate = LegacyRewardRate {
+            reward_num: contract.config.reward_num,
+            reward_denom: contract.config.reward_denom,
+            until_block: reward_block,
+        };
+        let pre_reward = contract.pre_reward.saturating_add(legacy_reward_rate.reward_since(contract.total_stake, contract.last_block_balance_change));
+
+        StakingContract {
+            owner_id: contract.owner_id,
+            ft_contract_id: contract.ft_contract_id,
+            config: Config::from(contract.config),
+            total_stake: contract.total_stake,
+            total_paid_reward: contract.total_paid_reward,
+            num_staker: contract.num_staker,
+            pre_reward,
+            last_block_balance_change: reward_block,
+            accounts: contract.accounts,
+            paused: contract.paused,
+            paused_block: contract.paused_block,
+            vesting_schedules: LookupMap::new(StorageKey::VestingKey),
+            referees: LookupMap::new(StorageKey::RefereesKey),
+            referral_earnings: LookupMap::new(StorageKey::ReferralEarningKey),
+            allowlist_enabled: false,
+            allowlist: UnorderedSet::new(StorageKey::AllowlistKey),
+            denylist: UnorderedSet::new(StorageKey::DenylistKey),
+            paused_blocks: 0,
+            roles: LookupMap::new(StorageKey::RolesKey),
+            pending_actions: UnorderedMap::new(StorageKey::PendingActionKey),
+            next_action_id: 0,
+            action_delay: DEFAULT_ACTION_DELAY,
+            num_legacy_accounts: contract.num_staker as u64,   // Every staker is stored in a legacy layout
+            upgrade_history: Vector::new(StorageKey::UpgradeHistoryKey),
+            account_ids: Vector::new(StorageKey::AccountIdsKey),     // Filled by `migrate_accounts`
+            account_indices: LookupMap::new(StorageKey::AccountIndexKey),
+            invariant_scan: None,
+            reward_remainder: 0,
+            total_unstaking: 0,         // Legacy unstaking balances are added when the accounts are rewritten
+            total_withdrawn: 0,
+            pending_operations: LookupMap::new(StorageKey::PendingOperationKey),
+            prepaid_storage: LookupMap::new(StorageKey::PrepaidStorageKey),
+            storage_pool: 0,
+            storage_pool_usage: LookupMap::new(StorageKey::StoragePoolUsageKey),
+            receipt_token_metadata: ReceiptTokenMetadata::default(),
+            positions: UnorderedMap::new(StorageKey::PositionKey),
+            positions_per_owner: LookupMap::new(StorageKey::PositionsPerOwnerKey),
+            next_position_id: 0,
+            total_boost: 0,
+            airdrop_balance: 0,
+            total_prepaid_storage: 0,   // Prepaid storage did not exist before version 3, so `prepaid_storage` is empty and its sum is 0
+            reward_per_share: 0,
+            reward_per_share_remainder: 0,
+            legacy_reward_rate,
+            total_reward_weight: contract.total_stake,     // Legacy accounts earn with their stake until rewritten
+            legacy_stake: contract.total_stake,
+            account_storage_usage: 0,                                       // Measured by `migrate`
+            storage_charges: LookupMap::new(StorageKey::StorageChargeKey),
+            pending_storage_refunds: 0,
+            account_migration_completed: false,
+            pending_transfers: 0,
+            version: CURRENT_VERSION,
+        }
+    }
+}
+
+#[near_bindgen]
+impl StakingContract {
+    // Upgrade the state from any older version to the current one
+    // Use the private macro to avoid others people calling it (only the contract can call)
+    // To migrate, use the command `near deploy path --initFunction migrate --initArgs '{}'`
+    // `deploy_staged_code` passes the hash of the staged code, and only then the upgrade is recorded
+    // Calling it again on a current state does nothing
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate(code_hash: Option<Base58CryptoHash>) -> Self {
+        let state = env::storage_read(b"STATE").expect("Cannot read old contract");
+        let contract = VersionedContract::read(&state);
+        let previous_version = contract.version();
+
+        let mut contract = contract.migrate();
+        contract.account_storage_usage = contract.internal_measure_account_storage_usage();
+        contract.internal_record_upgrade(previous_version, code_hash);
+        contract
+    }
+
+    // Rewrite legacy accounts in the current layout until the gas runs out
+    // Return the number of processed accounts, the next call should start from there
+    pub fn migrate_accounts(&mut self, account_ids: Vec<AccountId>) -> u64 {
+        self.assert_role(Role::Admin);
+        let mut num_processed = 0;
+        let mut num_migrated = 0;
+        for account_id in account_ids.iter() {
+            if env::used_gas() + MIGRATE_ACCOUNTS_GAS_RESERVE > env::prepaid_gas() {
+                break;
+            }
+            if let Some(upgradable_account) = self.accounts.get(account_id) {
+                if upgradable_account.is_legacy() {
+                    self.internal_save_account(account_id, Account::from(upgradable_account));
+                    num_migrated += 1;
+                }
+            }
+            num_processed += 1;
+        }
+
+        emit_event("accounts_migrated", json!({
+            "num_migrated": num_migrated,
+            "num_legacy_accounts": self.num_legacy_accounts,
+            "legacy_stake": U128(self.legacy_stake),
+        }));
+        num_processed
+    }
+
+    // Confirm that every legacy account is rewritten, after `get_legacy_accounts` finds none in the list of candidates
+    // Until then, the unstaking balances and the reward of the legacy accounts without stake are unknown,
+    // so `check_invariants` cannot report the solvency
+    pub fn complete_account_migration(&mut self) {
+        self.assert_role(Role::Admin);
+        assert_eq!(self.num_legacy_accounts, 0, "Legacy stakers remain");
+        self.account_migration_completed = true;
+        emit_event("account_migration_completed", json!({ "total_unstaking": U128(self.total_unstaking) }));
+    }
+
+    // LookupMap cannot be iterated, so the legacy stakers are counted from the pool values of version 2
+    // Both totals decrease every time a legacy entry is rewritten, lazily or by `migrate_accounts`
+    // Accounts without stake are not counted, as version 2 has no count of them (see `get_legacy_accounts`)
+    pub fn get_num_legacy_accounts(&self) -> u64 {
+        self.num_legacy_accounts
+    }
+
+    pub fn get_legacy_stake(&self) -> U128 {
+        U128(self.legacy_stake)
+    }
+
+    // Ids of the list which are still stored in a legacy layout, to find the accounts left to migrate
+    // from a list of candidates, such as the accounts which ever called `storage_deposit`
+    pub fn get_legacy_accounts(&self, account_ids: Vec<AccountId>) -> Vec<AccountId> {
+        account_ids.into_iter()
+            .filter(|account_id| self.accounts.get(account_id).is_some_and(|account| account.is_legacy()))
+            .collect()
+    }
+
+    // Dry run of `migrate_accounts`, show the values which would be written
+    pub fn preview_account_migration(&self, account_ids: Vec<AccountId>) -> Vec<AccountMigrationJson> {
+        account_ids.into_iter()
+            .map(|account_id| {
+                let upgradable_account = self.accounts.get(&account_id);
+                let stored_version = upgradable_account.as_ref().map(|account| account.version());
+                let account = upgradable_account.map(|upgradable_account| {
+                    let account = Account::from(upgradable_account);
+                    let new_reward = self.internal_calculate_new_reward(Some(&account));
+                    AccountJson::from(account_id.clone(), new_reward, account)
+                });
+                AccountMigrationJson { account_id, stored_version, account }
+            })
+            .collect()
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AccountMigrationJson {
+    pub account_id: AccountId,
+    pub stored_version: Option<u8>,     // None if the account is not registered
+    pub account: Option<AccountJson>,   // Values after the migration
+}
@@ -0,0 +1,201 @@
+use crate::*;
+use near_sdk::serde_json::json;
+use std::collections::HashMap;
+
+// The locked positions are NEP-171 tokens, with the enumeration (NEP-181) and metadata (NEP-177) extensions
+// The owner of a token is the account holding its stake, which can harvest the reward and unlock it
+// Approvals are not supported
+
+pub const NFT_ON_TRANSFER_GAS: Gas = 25_000_000_000_000;
+pub const NFT_RESOLVE_TRANSFER_GAS: Gas = 10_000_000_000_000;
+
+pub const NFT_METADATA_SPEC: &str = "nft-1.0.0";
+
+#[derive(Deserialize, Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NFTContractMetadata {
+    pub spec: String,
+    pub name: String,
+    pub symbol: String,
+    pub icon: Option<String>,
+    pub base_uri: Option<String>,
+    pub reference: Option<String>,
+    pub reference_hash: Option<Base64VecU8>,
+}
+
+#[derive(Deserialize, Serialize, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenMetadata {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub media: Option<String>,
+    pub media_hash: Option<Base64VecU8>,
+    pub copies: Option<u64>,
+    pub issued_at: Option<String>,
+    pub expires_at: Option<String>,
+    pub starts_at: Option<String>,
+    pub updated_at: Option<String>,
+    pub extra: Option<String>,      // JSON of the position: amount, lock end epoch, membership and boost
+    pub reference: Option<String>,
+    pub reference_hash: Option<Base64VecU8>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct JsonToken {
+    pub token_id: TokenId,
+    pub owner_id: AccountId,
+    pub metadata: TokenMetadata,
+    pub approved_account_ids: HashMap<AccountId, u64>,
+}
+
+impl JsonToken {
+    pub fn from(token_id: TokenId, position: Position) -> Self {
+        let extra = json!({
+            "amount": U128(position.amount),
+            "lock_end_epoch": position.lock_end_epoch,
+            "membership": position.membership,
+            "boost_bps": position.boost_bps,
+            "boost_balance": U128(position.boost_balance),
+        });
+        JsonToken {
+            metadata: TokenMetadata {
+                title: Some(format!("Locked stake #{}", token_id)),
+                description: Some(format!("{} staked tokens locked until epoch {}", position.amount, position.lock_end_epoch)),
+                extra: Some(extra.to_string()),
+                ..TokenMetadata::default()
+            },
+            token_id,
+            owner_id: position.owner_id,
+            approved_account_ids: HashMap::new(),
+        }
+    }
+}
+
+// Return true to give the token back to the previous owner
+#[ext_contract(ext_nft_receiver)]
+pub trait NonFungibleTokenReceiver {
+    fn nft_on_transfer(&mut self, sender_id: AccountId, previous_owner_id: AccountId, token_id: TokenId, msg: String) -> PromiseOrValue<bool>;
+}
+
+#[near_bindgen]
+impl StakingContract {
+    #[payable]
+    pub fn nft_transfer(&mut self, receiver_id: AccountId, token_id: TokenId, approval_id: Option<u64>, memo: Option<String>) {
+        assert_one_yocto();
+        assert!(approval_id.is_none(), "Approvals are not supported");
+        let sender_id = env::predecessor_account_id();
+        self.internal_checked_transfer_position(&sender_id, &receiver_id, &token_id, memo);
+    }
+
+    #[payable]
+    pub fn nft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        token_id: TokenId,
+        approval_id: Option<u64>,
+        memo: Option<String>,
+        msg: String
+    ) -> Promise {
+        assert_one_yocto();
+        assert!(approval_id.is_none(), "Approvals are not supported");
+        let sender_id = env::predecessor_account_id();
+        self.internal_checked_transfer_position(&sender_id, &receiver_id, &token_id, memo);
+        self.internal_lock_account(&sender_id, PendingOperation::TransferPosition);
+
+        ext_nft_receiver::nft_on_transfer(
+            sender_id.clone(),
+            sender_id.clone(),
+            token_id.clone(),
+            msg,
+            &receiver_id,
+            0,
+            NFT_ON_TRANSFER_GAS
+        ).then(ext_self::nft_resolve_transfer(
+            sender_id,
+            receiver_id,
+            token_id,
+            None,
+            &env::current_account_id(),
+            0,
+            NFT_RESOLVE_TRANSFER_GAS
+        ))
+    }
+
+    // Return true if the token stays with the receiver
+    #[private]
+    pub fn nft_resolve_transfer(
+        &mut self,
+        previous_owner_id: AccountId,
+        receiver_id: AccountId,
+        token_id: TokenId,
+        approved_account_ids: Option<HashMap<AccountId, u64>>
+    ) -> bool {
+        let _ = approved_account_ids;   // Approvals are not supported
+        assert_eq!(env::promise_results_count(), 1, "Too many promise results");
+        self.internal_unlock_account(&previous_owner_id);
+        let must_return = match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<bool>(&value).unwrap_or(true),
+            PromiseResult::Failed => true,
+        };
+        if !must_return {
+            return true;
+        }
+
+        // The receiver may have moved the token meanwhile, and a receiver with an operation in flight is not touched
+        let still_owned = self.positions.get(&token_id).is_some_and(|position| position.owner_id == receiver_id);
+        if !still_owned || self.pending_operations.get(&receiver_id).is_some() {
+            return true;
+        }
+        self.internal_transfer_position(&receiver_id, &previous_owner_id, &token_id, Some("refund".to_string()));
+        false
+    }
+
+    pub fn nft_token(&self, token_id: TokenId) -> Option<JsonToken> {
+        self.positions.get(&token_id).map(|position| JsonToken::from(token_id, position))
+    }
+
+    pub fn nft_total_supply(&self) -> U128 {
+        U128(self.positions.len() as u128)
+    }
+
+    pub fn nft_tokens(&self, from_index: Option<U128>, limit: Option<u64>) -> Vec<JsonToken> {
+        let token_ids = self.positions.keys_as_vector();
+        let positions = self.positions.values_as_vector();
+        page_range(token_ids.len(), from_index.map(|from_index| from_index.0 as u64), limit)
+            .map(|index| JsonToken::from(token_ids.get(index).unwrap(), positions.get(index).unwrap()))
+            .collect()
+    }
+
+    pub fn nft_supply_for_owner(&self, account_id: AccountId) -> U128 {
+        U128(self.positions_per_owner.get(&account_id).map_or(0, |token_ids| token_ids.len() as u128))
+    }
+
+    pub fn nft_tokens_for_owner(&self, account_id: AccountId, from_index: Option<U128>, limit: Option<u64>) -> Vec<JsonToken> {
+        let token_ids = match self.positions_per_owner.get(&account_id) {
+            Some(token_ids) => token_ids,
+            None => return vec![],
+        };
+        let token_ids = token_ids.as_vector();
+        page_range(token_ids.len(), from_index.map(|from_index| from_index.0 as u64), limit)
+            .map(|index| {
+                let token_id = token_ids.get(index).unwrap();
+                let position = self.positions.get(&token_id).unwrap();
+                JsonToken::from(token_id, position)
+            })
+            .collect()
+    }
+
+    pub fn nft_metadata(&self) -> NFTContractMetadata {
+        NFTContractMetadata {
+            spec: NFT_METADATA_SPEC.to_string(),
+            name: format!("Locked {}", self.receipt_token_metadata.name),
+            symbol: format!("L{}", self.receipt_token_metadata.symbol),
+            icon: self.receipt_token_metadata.icon.clone(),
+            base_uri: None,
+            reference: None,
+            reference_hash: None,
+        }
+    }
+}
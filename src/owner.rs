@@ -0,0 +1,73 @@
+use crate::*;
+use near_sdk::serde_json::json;
+
+// Functions that only the owner or the respective role can call
+#[near_bindgen]
+impl StakingContract {
+    // Stop the reward and new stakes, stakers can use `emergency_withdraw` meanwhile
+    pub fn pause(&mut self) {
+        self.assert_role(Role::Pauser);
+        assert!(!self.paused, "Contract is already paused");
+        self.paused = true;
+        self.paused_block = env::block_index();
+        emit_event("pause", json!({ "paused_block": self.paused_block }));
+    }
+
+    // The paused blocks are skipped when counting reward
+    pub fn unpause(&mut self) {
+        self.assert_role(Role::Pauser);
+        assert!(self.paused, "Contract is not paused");
+        self.paused_blocks += env::block_index() - self.paused_block;
+        self.paused = false;
+        emit_event("unpause", json!({ "paused_blocks": self.paused_blocks }));
+    }
+
+    // When enabled, only allowlisted accounts can register and stake
+    pub fn set_allowlist_enabled(&mut self, enabled: bool) {
+        self.assert_role(Role::Admin);
+        self.allowlist_enabled = enabled;
+        emit_event("allowlist_mode", json!({ "enabled": enabled }));
+    }
+
+    pub fn add_to_allowlist(&mut self, account_ids: Vec<AccountId>) {
+        self.assert_role(Role::Admin);
+        for account_id in account_ids.iter() {
+            self.allowlist.insert(account_id);
+        }
+        emit_event("allowlist_add", json!({ "account_ids": account_ids }));
+    }
+
+    pub fn remove_from_allowlist(&mut self, account_ids: Vec<AccountId>) {
+        self.assert_role(Role::Admin);
+        for account_id in account_ids.iter() {
+            self.allowlist.remove(account_id);
+        }
+        emit_event("allowlist_remove", json!({ "account_ids": account_ids }));
+    }
+
+    // Unlock an account whose callback never ran, e.g. because it ran out of gas
+    // A callback which still arrives only restores its amounts onto the account
+    pub fn clear_pending_operation(&mut self, account_id: AccountId) {
+        self.assert_role(Role::Admin);
+        let operation = self.pending_operations.get(&account_id).expect("No pending operation");
+        self.internal_unlock_account(&account_id);
+        emit_event("pending_operation_cleared", json!({ "account_id": account_id, "operation": operation }));
+    }
+
+    // Denied accounts cannot stake anymore, but can still unstake and withdraw
+    pub fn add_to_denylist(&mut self, account_ids: Vec<AccountId>) {
+        self.assert_role(Role::Admin);
+        for account_id in account_ids.iter() {
+            self.denylist.insert(account_id);
+        }
+        emit_event("denylist_add", json!({ "account_ids": account_ids }));
+    }
+
+    pub fn remove_from_denylist(&mut self, account_ids: Vec<AccountId>) {
+        self.assert_role(Role::Admin);
+        for account_id in account_ids.iter() {
+            self.denylist.remove(account_id);
+        }
+        emit_event("denylist_remove", json!({ "account_ids": account_ids }));
+    }
+}
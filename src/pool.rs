@@ -6,7 +6,15 @@ pub struct PoolJson {
     pub total_stake_balance: U128,
     pub total_reward: U128,
     pub total_staker: U128,
-    pub is_paused: bool
+    pub is_paused: bool,
+    pub total_paid_reward: U128,
+    pub total_unstaking: U128,
+    pub total_withdrawn: U128,
+    pub config: Config,
+    pub version: U128,
+    pub paused_block: BlockHeight,
+    pub ft_contract_id: AccountId,
+    pub current_epoch: EpochHeight,
 }
 
 impl PoolJson {
@@ -16,6 +24,24 @@ impl PoolJson {
             total_reward: U128(pool.pre_reward + pool.internal_calculate_new_reward(None)),
             total_staker: U128(pool.num_staker),
             is_paused: pool.paused,
+            total_paid_reward: U128(pool.total_paid_reward),
+            total_unstaking: U128(pool.total_unstaking),
+            total_withdrawn: U128(pool.total_withdrawn),
+            config: pool.config,
+            version: U128(pool.version),
+            paused_block: pool.paused_block,
+            ft_contract_id: pool.ft_contract_id.clone(),
+            current_epoch: env::epoch_height(),
         }
     }
-}
\ No newline at end of file
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(crate="near_sdk::serde")]
+pub struct RewardRateJson {
+    pub reward_num: u32,                // Reward per staked unit and per block is `reward_num / reward_denom`
+    pub reward_denom: U64,
+    pub pool_reward_per_block: U128,    // Reward of the whole stake for the next block, with the membership multipliers
+    pub is_paused: bool,                // No reward accrues while the contract is paused
+}
+
@@ -0,0 +1,151 @@
+use crate::*;
+use near_sdk::serde_json::json;
+
+pub type TokenId = String;
+
+// When set in `Config`, stakers can lock a part of their stake until an epoch, for a reward boost
+// The boost grows linearly with the lock duration, up to `max_boost_bps` for `max_lock_epochs`
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PositionConfig {
+    pub min_lock_epochs: u64,
+    pub max_lock_epochs: u64,
+    pub max_boost_bps: u32,
+}
+
+impl PositionConfig {
+    pub fn boost_bps(&self, lock_epochs: u64) -> u32 {
+        (self.max_boost_bps as u64 * lock_epochs).checked_div(self.max_lock_epochs).unwrap_or(0) as u32
+    }
+}
+
+// A locked part of the stake, which is a NEP-171 token (see `nft.rs`)
+// The stake stays in the account of the owner as `locked_balance`, and moves with the token
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Position {
+    pub owner_id: AccountId,
+    pub amount: Balance,
+    pub boost_bps: u32,
+    pub boost_balance: Balance,     // `amount * boost_bps`, earning reward on top of the amount
+    pub membership: Membership,     // Tier of the account which locked the stake
+    pub lock_start_epoch: EpochHeight,
+    pub lock_end_epoch: EpochHeight,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PositionJson {
+    pub token_id: TokenId,
+    pub owner_id: AccountId,
+    pub amount: U128,
+    pub boost_bps: u32,
+    pub boost_balance: U128,
+    pub membership: Membership,
+    pub lock_start_epoch: EpochHeight,
+    pub lock_end_epoch: EpochHeight,
+    pub can_unlock: bool,
+}
+
+impl PositionJson {
+    pub fn from(token_id: TokenId, position: Position) -> Self {
+        PositionJson {
+            token_id,
+            owner_id: position.owner_id,
+            amount: U128(position.amount),
+            boost_bps: position.boost_bps,
+            boost_balance: U128(position.boost_balance),
+            membership: position.membership,
+            lock_start_epoch: position.lock_start_epoch,
+            lock_end_epoch: position.lock_end_epoch,
+            can_unlock: position.lock_end_epoch <= env::epoch_height(),
+        }
+    }
+}
+
+#[near_bindgen]
+impl StakingContract {
+    // Lock `amount` of the stake for `lock_epochs` epochs, and mint the token of the position
+    // The attached deposit pays the storage of the position
+    #[payable]
+    pub fn lock_stake(&mut self, amount: U128, lock_epochs: u64) -> TokenId {
+        assert_at_least_one_yocto();
+        assert!(!self.is_paused(), "Contract is paused");
+        let position_config = self.config.position.expect("Locked positions are disabled");
+        assert!(
+            lock_epochs >= position_config.min_lock_epochs && lock_epochs <= position_config.max_lock_epochs,
+            "Lock duration must be between {} and {} epochs",
+            position_config.min_lock_epochs,
+            position_config.max_lock_epochs
+        );
+        assert!(amount.0 > 0, "Amount must be positive");
+
+        let account_id = env::predecessor_account_id();
+        self.assert_not_locked(&account_id);
+        let upgradable_account = self.accounts.get(&account_id).expect("Account not found, please registry first");
+        let mut account = Account::from(upgradable_account);
+        assert!(amount.0 <= account.stake_balance - account.locked_balance, "Cannot lock more than the unlocked stake");
+
+        let storage_usage_before = env::storage_usage();
+        let boost_bps = position_config.boost_bps(lock_epochs);
+        let boost_balance = mul_div(amount.0, boost_bps as Balance, BPS_DENOMINATOR as Balance);
+
+        // The boost earns reward from now on, so both rewards are settled before
+        self.internal_update_account_reward(&mut account);
+        account.locked_balance += amount.0;
+        account.boost_balance += boost_balance;
+        let membership = account.membership.clone();
+        self.internal_save_account(&account_id, account);
+        self.internal_update_pool_reward();
+        self.total_boost += boost_balance;
+
+        let token_id = self.next_position_id.to_string();
+        self.next_position_id += 1;
+        let epoch = env::epoch_height();
+        let position = Position {
+            owner_id: account_id.clone(),
+            amount: amount.0,
+            boost_bps,
+            boost_balance,
+            membership,
+            lock_start_epoch: epoch,
+            lock_end_epoch: epoch + lock_epochs,
+        };
+        self.positions.insert(&token_id, &position);
+        self.internal_add_position_to_owner(&account_id, &token_id);
+        emit_nft_event("nft_mint", json!({ "owner_id": account_id, "token_ids": [token_id] }));
+
+        refund_deposit(env::storage_usage() - storage_usage_before);
+        token_id
+    }
+
+    // Burn the token of an expired position, its amount becomes a plain stake again
+    #[payable]
+    pub fn unlock_position(&mut self, token_id: TokenId) {
+        assert_one_yocto();
+        assert!(!self.is_paused(), "Contract is paused");
+        let account_id = env::predecessor_account_id();
+        self.assert_not_locked(&account_id);
+        let position = self.positions.get(&token_id).expect("Position not found");
+        assert_eq!(position.owner_id, account_id, "Only the owner of the position can unlock it");
+        assert!(position.lock_end_epoch <= env::epoch_height(), "Position is locked until epoch {}", position.lock_end_epoch);
+
+        let mut account = Account::from(self.accounts.get(&account_id).unwrap());
+        self.internal_update_account_reward(&mut account);
+        account.locked_balance -= position.amount;
+        account.boost_balance -= position.boost_balance;
+        self.internal_save_account(&account_id, account);
+        self.internal_update_pool_reward();
+        self.total_boost -= position.boost_balance;
+
+        self.internal_remove_position(&token_id, &position);
+        emit_nft_event("nft_burn", json!({ "owner_id": account_id, "token_ids": [token_id] }));
+    }
+
+    pub fn get_position(&self, token_id: TokenId) -> Option<PositionJson> {
+        self.positions.get(&token_id).map(|position| PositionJson::from(token_id, position))
+    }
+
+    pub fn get_total_boost(&self) -> U128 {
+        U128(self.total_boost)
+    }
+}
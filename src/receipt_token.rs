@@ -0,0 +1,104 @@
+use crate::*;
+use near_sdk::serde_json::json;
+
+// The contract is a NEP-141 token as well, whose balances are the stake balances
+// Staking mints it, unstaking burns it, and a transfer moves the stake with its future reward
+// The reward already earned stays with the sender
+
+pub const FT_ON_TRANSFER_GAS: Gas = 25_000_000_000_000;
+pub const FT_RESOLVE_TRANSFER_GAS: Gas = 10_000_000_000_000;
+
+pub const FT_METADATA_SPEC: &str = "ft-1.0.0";
+
+// NEP-148 metadata, the decimals should be the ones of the staked token
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ReceiptTokenMetadata {
+    pub spec: String,
+    pub name: String,
+    pub symbol: String,
+    pub icon: Option<String>,
+    pub reference: Option<String>,
+    pub reference_hash: Option<Base64VecU8>,
+    pub decimals: u8,
+}
+
+impl Default for ReceiptTokenMetadata {
+    fn default() -> Self {
+        ReceiptTokenMetadata {
+            spec: FT_METADATA_SPEC.to_string(),
+            name: "Staked token".to_string(),
+            symbol: "STAKED".to_string(),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals: 24,
+        }
+    }
+}
+
+#[ext_contract(ext_ft_receiver)]
+pub trait ReceiptTokenReceiver {
+    fn ft_on_transfer(&mut self, sender_id: AccountId, amount: U128, msg: String) -> PromiseOrValue<U128>;
+}
+
+#[near_bindgen]
+impl StakingContract {
+    #[payable]
+    pub fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>) {
+        assert_one_yocto();
+        let sender_id = env::predecessor_account_id();
+        self.internal_checked_transfer_stake(&sender_id, &receiver_id, amount.0, memo);
+    }
+
+    #[payable]
+    pub fn ft_transfer_call(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>, msg: String) -> Promise {
+        assert_one_yocto();
+        let sender_id = env::predecessor_account_id();
+        self.internal_checked_transfer_stake(&sender_id, &receiver_id, amount.0, memo);
+        self.internal_lock_account(&sender_id, PendingOperation::TransferStake);
+
+        ext_ft_receiver::ft_on_transfer(
+            sender_id.clone(),
+            amount,
+            msg,
+            &receiver_id,
+            0,
+            FT_ON_TRANSFER_GAS
+        ).then(ext_self::ft_resolve_transfer(
+            sender_id,
+            receiver_id,
+            amount,
+            &env::current_account_id(),
+            0,
+            FT_RESOLVE_TRANSFER_GAS
+        ))
+    }
+
+    #[private]
+    pub fn ft_resolve_transfer(&mut self, sender_id: AccountId, receiver_id: AccountId, amount: U128) -> U128 {
+        self.internal_resolve_stake_transfer(&sender_id, &receiver_id, amount)
+    }
+
+    pub fn ft_total_supply(&self) -> U128 {
+        U128(self.total_stake)
+    }
+
+    // The balance includes the stake of locked positions, which `ft_transfer` refuses until they are unlocked
+    // (`get_account_info` has the locked part), so that the balances add up to `ft_total_supply`
+    pub fn ft_balance_of(&self, account_id: AccountId) -> U128 {
+        let stake_balance = self.accounts.get(&account_id).map_or(0, |account| Account::from(account).stake_balance);
+        U128(stake_balance)
+    }
+
+    pub fn ft_metadata(&self) -> ReceiptTokenMetadata {
+        self.receipt_token_metadata.clone()
+    }
+
+    pub fn set_ft_metadata(&mut self, metadata: ReceiptTokenMetadata) {
+        self.assert_role(Role::Admin);
+        assert_eq!(metadata.spec, FT_METADATA_SPEC, "Unsupported metadata spec");
+        self.receipt_token_metadata = metadata;
+        emit_event("ft_metadata_update", json!({ "metadata": self.receipt_token_metadata }));
+    }
+}
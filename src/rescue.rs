@@ -0,0 +1,124 @@
+use crate::*;
+use near_sdk::serde_json::json;
+
+pub const RESCUE_SURPLUS_CALLBACK_GAS: Gas = 40_000_000_000_000;
+pub const RESCUE_CALLBACK_GAS: Gas = 10_000_000_000_000;
+
+// Recovery of tokens and NEAR sent to the contract by mistake, by the treasury managers
+// The staked token can only be rescued over what the contract owes of it (see `internal_staked_token_liabilities`)
+#[near_bindgen]
+impl StakingContract {
+    // Transfer a NEP-141 token sent to the contract by mistake
+    // Any other token is not owed by the contract, the staked token is first read with `ft_balance_of`,
+    // and only its surplus over the liabilities is transferred
+    #[payable]
+    pub fn rescue_ft(&mut self, token_id: AccountId, receiver_id: AccountId, amount: U128) -> Promise {
+        assert_one_yocto();
+        self.assert_role(Role::TreasuryManager);
+        assert!(amount.0 > 0, "Amount must be positive");
+        if token_id == self.ft_contract_id {
+            return ext_ft::ft_balance_of(
+                env::current_account_id(),
+                &self.ft_contract_id,
+                0,
+                FT_BALANCE_OF_GAS
+            ).then(ext_self::ft_rescue_surplus_callback(
+                receiver_id,
+                amount,
+                &env::current_account_id(),
+                0,
+                RESCUE_SURPLUS_CALLBACK_GAS
+            ));
+        }
+
+        emit_event("ft_rescued", json!({
+            "token_id": token_id,
+            "receiver_id": receiver_id,
+            "amount": amount,
+        }));
+        ext_ft::ft_transfer(
+            receiver_id,
+            amount,
+            Some("Rescue from staking contract".to_string()),
+            &token_id,
+            1,
+            FT_TRANSFER_GAS
+        )
+    }
+
+    #[private]
+    pub fn ft_rescue_surplus_callback(&mut self, receiver_id: AccountId, amount: U128) -> Promise {
+        assert_eq!(env::promise_results_count(), 1, "Too many promise results");
+        let balance: U128 = match env::promise_result(0) {
+            PromiseResult::Successful(value) => near_sdk::serde_json::from_slice(&value).expect("Invalid balance"),
+            _ => env::panic("Cannot read the token balance".as_bytes()),
+        };
+        let surplus = balance.0.saturating_sub(self.internal_staked_token_liabilities());
+        assert!(amount.0 <= surplus, "Cannot rescue more than the surplus {} of the staked token", surplus);
+        self.pending_transfers += amount.0;
+        emit_event("ft_rescued", json!({
+            "token_id": self.ft_contract_id,
+            "receiver_id": receiver_id,
+            "amount": amount,
+        }));
+
+        ext_ft::ft_transfer(
+            receiver_id,
+            amount,
+            Some("Rescue from staking contract".to_string()),
+            &self.ft_contract_id,
+            1,
+            FT_TRANSFER_GAS
+        ).then(ext_self::ft_rescue_callback(
+            amount,
+            &env::current_account_id(),
+            0,
+            RESCUE_CALLBACK_GAS
+        ))
+    }
+
+    #[private]
+    pub fn ft_rescue_callback(&mut self, amount: U128) -> U128 {
+        assert_eq!(env::promise_results_count(), 1, "Too many promise results");
+        self.pending_transfers -= amount.0;
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_value) => amount,
+            PromiseResult::Failed => U128(0),
+        }
+    }
+
+    // Transfer NEAR which is not needed for the storage, the storage pool, the prepaid storage
+    // or the storage refunds of the closing accounts
+    #[payable]
+    pub fn rescue_near(&mut self, receiver_id: AccountId, amount: U128) -> Promise {
+        assert_one_yocto();
+        self.assert_role(Role::TreasuryManager);
+        let rescuable = self.get_rescuable_near().0;
+        assert!(amount.0 > 0 && amount.0 <= rescuable, "Cannot rescue more than {} yoctoNEAR", rescuable);
+        emit_event("near_rescued", json!({
+            "receiver_id": receiver_id,
+            "amount": amount,
+        }));
+        Promise::new(receiver_id).transfer(amount.0)
+    }
+
+    // NEAR balance over the liabilities of the contract
+    pub fn get_rescuable_near(&self) -> U128 {
+        let storage_cost = env::storage_byte_cost() * Balance::from(env::storage_usage());
+        let liabilities = storage_cost + self.storage_pool + self.total_prepaid_storage + self.pending_storage_refunds;
+        U128(env::account_balance().saturating_sub(liabilities))
+    }
+}
+
+impl StakingContract {
+    // Staked tokens owed by the contract: the stake, the unstaking balances, the airdrop balance, the transfers in flight,
+    // and the reward accrued by the pool and not paid yet, which includes the vesting schedules
+    // The reward forfeited by emergency withdrawals is still counted, so the surplus is never overestimated
+    // The unstaking balances of the legacy accounts are only known once they are all rewritten
+    pub(crate) fn internal_staked_token_liabilities(&self) -> Balance {
+        assert!(self.account_migration_completed, "Legacy accounts remain, complete the account migration first");
+        let reward_reserve = self.pre_reward.saturating_add(self.internal_calculate_new_reward(None)).saturating_sub(self.total_paid_reward);
+        self.total_stake + self.total_unstaking + self.airdrop_balance + self.pending_transfers + reward_reserve
+    }
+}
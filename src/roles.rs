@@ -0,0 +1,43 @@
+use crate::*;
+use near_sdk::serde_json::json;
+
+// Operational roles, the owner implicitly holds all of them
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum Role {
+    Admin,              // Grant and revoke roles, manage the access lists
+    Pauser,             // Pause and unpause the contract
+    ConfigManager,      // Change the reward economics
+    TreasuryManager,    // Move funds that are not owed to stakers
+}
+
+#[near_bindgen]
+impl StakingContract {
+    pub fn grant_role(&mut self, role: Role, account_id: AccountId) {
+        self.assert_role(Role::Admin);
+        let mut members = self.internal_role_members(role);
+        members.insert(&account_id);
+        self.roles.insert(&role, &members);
+        emit_event("role_granted", json!({ "role": role, "account_id": account_id }));
+    }
+
+    pub fn revoke_role(&mut self, role: Role, account_id: AccountId) {
+        self.assert_role(Role::Admin);
+        let mut members = self.internal_role_members(role);
+        members.remove(&account_id);
+        self.roles.insert(&role, &members);
+        emit_event("role_revoked", json!({ "role": role, "account_id": account_id }));
+    }
+
+    pub fn has_role(&self, role: Role, account_id: AccountId) -> bool {
+        account_id == self.owner_id || self.internal_role_members(role).contains(&account_id)
+    }
+
+    pub fn get_role_members(&self, role: Role, from_index: Option<u64>, limit: Option<u64>) -> Vec<AccountId> {
+        let members = self.internal_role_members(role);
+        let members = members.as_vector();
+        page_range(members.len(), from_index, limit)
+            .map(|index| members.get(index).unwrap())
+            .collect()
+    }
+}
@@ -4,7 +4,7 @@ use crate::*;
 pub struct StakingContractV1 {
     pub owner_id: AccountId,                                    // ID of contract owner
     pub ft_contract_id: AccountId,                              // ID of fungible token contract
-    pub config: Config,                                         // Config incentive rule
+    pub config: ConfigV1,                                       // Config incentive rule
     pub total_stake: Balance,                                   // Total stake balance
     pub total_paid_reward: Balance,                             // Total reward paid for stakers
     pub num_staker: u128,                                       // The number of stakers
@@ -0,0 +1,160 @@
+use crate::*;
+use near_sdk::serde_json::json;
+
+// When set in `Config`, accounts staking without registration are registered automatically
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AutoRegisterConfig {
+    pub max_storage_per_account: U128,      // NEAR that the storage pool can spend on the registration of one account
+}
+
+// Who paid the registration of an account
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum StoragePayer {
+    Account(AccountId),
+    StoragePool,
+}
+
+// Cost of the registration of an account, given back to its payer when the account is closed
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageCharge {
+    pub payer: StoragePayer,
+    pub amount: U128,
+}
+
+// NEAR prepaid by a sender, to register the beneficiaries of its stakes (see `StakeMsg`)
+#[near_bindgen]
+impl StakingContract {
+    #[payable]
+    pub fn prepay_storage(&mut self) -> U128 {
+        assert_at_least_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let prepaid = self.prepaid_storage.get(&account_id).unwrap_or(0) + env::attached_deposit();
+        self.prepaid_storage.insert(&account_id, &prepaid);
+        self.total_prepaid_storage += env::attached_deposit();
+        U128(prepaid)
+    }
+
+    // Withdraw the unused prepaid storage, all of it by default
+    #[payable]
+    pub fn withdraw_prepaid_storage(&mut self, amount: Option<U128>) -> Promise {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let prepaid = self.prepaid_storage.get(&account_id).unwrap_or(0);
+        let amount = amount.map_or(prepaid, |amount| amount.0);
+        assert!(amount > 0 && amount <= prepaid, "Invalid amount of prepaid storage");
+
+        if amount == prepaid {
+            self.prepaid_storage.remove(&account_id);
+        } else {
+            self.prepaid_storage.insert(&account_id, &(prepaid - amount));
+        }
+        self.total_prepaid_storage -= amount;
+        Promise::new(account_id).transfer(amount)
+    }
+
+    pub fn get_prepaid_storage(&self, account_id: AccountId) -> U128 {
+        U128(self.prepaid_storage.get(&account_id).unwrap_or(0))
+    }
+}
+
+// Storage pool, paying the automatic registrations
+#[near_bindgen]
+impl StakingContract {
+    #[payable]
+    pub fn fund_storage_pool(&mut self) -> U128 {
+        self.assert_role(Role::TreasuryManager);
+        assert_at_least_one_yocto();
+        self.storage_pool += env::attached_deposit();
+        emit_event("storage_pool_funded", json!({
+            "amount": U128(env::attached_deposit()),
+            "storage_pool": U128(self.storage_pool),
+        }));
+        U128(self.storage_pool)
+    }
+
+    pub fn withdraw_storage_pool(&mut self, amount: U128) -> Promise {
+        self.assert_role(Role::TreasuryManager);
+        assert!(amount.0 <= self.storage_pool, "Not enough NEAR in the storage pool");
+        self.storage_pool -= amount.0;
+        emit_event("storage_pool_withdrawn", json!({
+            "amount": amount,
+            "storage_pool": U128(self.storage_pool),
+        }));
+        Promise::new(env::predecessor_account_id()).transfer(amount.0)
+    }
+
+    pub fn get_storage_pool(&self) -> U128 {
+        U128(self.storage_pool)
+    }
+
+    pub fn get_storage_pool_usage(&self, account_id: AccountId) -> U128 {
+        U128(self.storage_pool_usage.get(&account_id).unwrap_or(0))
+    }
+}
+
+// NEP-145 storage management
+// The storage of an account is its registration, whose cost depends on the length of the ids, so nothing is ever available
+// to withdraw, and the total is the cost charged at registration, whoever paid it (see `StorageCharge`)
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalance {
+    pub total: U128,
+    pub available: U128,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalanceBounds {
+    pub min: U128,
+    pub max: Option<U128>,
+}
+
+#[near_bindgen]
+impl StakingContract {
+    pub fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        let registration_cost = U128(env::storage_byte_cost() * Balance::from(self.account_storage_usage));
+        StorageBalanceBounds {
+            min: registration_cost,
+            max: Some(registration_cost),
+        }
+    }
+
+    // Accounts registered before the charges were recorded report the cost of the largest registration
+    pub fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.accounts.get(&account_id).map(|_| StorageBalance {
+            total: self.storage_charges.get(&account_id).map_or_else(|| self.storage_balance_bounds().min, |storage_charge| storage_charge.amount),
+            available: U128(0),
+        })
+    }
+
+    #[payable]
+    pub fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let storage_balance = self.storage_balance_of(account_id).expect("Account not found");
+        assert!(amount.is_none_or(|amount| amount.0 == 0), "The storage balance has nothing available to withdraw");
+        storage_balance
+    }
+
+    // Only an empty account can be unregistered, `close_account` exits the others
+    #[payable]
+    pub fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        assert_one_yocto();
+        assert!(!force.unwrap_or(false), "Forced unregistration is not supported, use close_account");
+        let account_id = env::predecessor_account_id();
+        let account = match self.accounts.get(&account_id) {
+            Some(upgradable_account) => Account::from(upgradable_account),
+            None => return false,
+        };
+        let reward = account.pre_reward + self.internal_calculate_new_reward(Some(&account));
+        assert!(
+            account.stake_balance + account.unstake_balance + reward == 0 && self.internal_get_vesting_schedule(&account_id).is_none(),
+            "The account still has funds, use close_account"
+        );
+        self.close_account();
+        true
+    }
+}
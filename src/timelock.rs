@@ -0,0 +1,133 @@
+use crate::*;
+use near_sdk::serde_json::json;
+
+// One day, in nanoseconds
+pub const DEFAULT_ACTION_DELAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Admin changes that only take effect after the action delay
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub enum AdminAction {
+    UpdateConfig { config: Config },
+    UpdateFtContract { ft_contract_id: AccountId },
+    TransferOwnership { owner_id: AccountId },
+    UpdateActionDelay { action_delay: U64 },
+}
+
+impl AdminAction {
+    // `None` when only the owner can queue, execute or cancel the action
+    pub fn required_role(&self) -> Option<Role> {
+        match self {
+            AdminAction::UpdateConfig { .. } => Some(Role::ConfigManager),
+            AdminAction::TransferOwnership { .. } => None,
+            _ => Some(Role::Admin),
+        }
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PendingAction {
+    pub action: AdminAction,
+    pub queued_at: Timestamp,
+    pub executable_at: Timestamp,       // Earliest timestamp to execute the action
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PendingActionJson {
+    pub action_id: u64,
+    pub action: AdminAction,
+    pub queued_at: Timestamp,
+    pub executable_at: Timestamp,
+}
+
+#[near_bindgen]
+impl StakingContract {
+    pub fn queue_action(&mut self, action: AdminAction) -> u64 {
+        self.assert_action_permission(&action);
+        if let AdminAction::UpdateConfig { config } = &action {
+            config.assert_valid();
+        }
+        if let AdminAction::UpdateConfig { config } = &action {
+            config.assert_valid();
+        }
+        let action_id = self.next_action_id;
+        let pending_action = PendingAction {
+            action,
+            queued_at: env::block_timestamp(),
+            executable_at: env::block_timestamp() + self.action_delay,
+        };
+        self.pending_actions.insert(&action_id, &pending_action);
+        self.next_action_id += 1;
+
+        emit_event("action_queued", json!({
+            "action_id": action_id,
+            "action": pending_action.action,
+            "executable_at": pending_action.executable_at,
+        }));
+        action_id
+    }
+
+    pub fn execute_action(&mut self, action_id: u64) {
+        let pending_action = self.pending_actions.get(&action_id).expect("Action not found");
+        self.assert_action_permission(&pending_action.action);
+        assert!(env::block_timestamp() >= pending_action.executable_at, "Action is still timelocked");
+        self.pending_actions.remove(&action_id);
+
+        match pending_action.action.clone() {
+            AdminAction::UpdateConfig { config } => {
+                // Settle the reward per share with the old rate before switching, so the new rate is not retroactive
+                // Its remainder is in units of the old `reward_denom`, so it is dropped (less than one unit of the reward per share)
+                self.internal_update_pool_reward();
+                self.reward_per_share_remainder = 0;
+                self.config = config;
+            },
+            AdminAction::UpdateFtContract { ft_contract_id } => {
+                assert_eq!(self.total_stake, 0, "Cannot change the token while tokens are staked");
+                self.ft_contract_id = ft_contract_id;
+            },
+            AdminAction::TransferOwnership { owner_id } => {
+                self.owner_id = owner_id;
+            },
+            AdminAction::UpdateActionDelay { action_delay } => {
+                self.action_delay = action_delay.0;
+            },
+        }
+        emit_event("action_executed", json!({ "action_id": action_id, "action": pending_action.action }));
+    }
+
+    pub fn cancel_action(&mut self, action_id: u64) {
+        let pending_action = self.pending_actions.get(&action_id).expect("Action not found");
+        self.assert_action_permission(&pending_action.action);
+        self.pending_actions.remove(&action_id);
+        emit_event("action_cancelled", json!({ "action_id": action_id }));
+    }
+
+    pub fn get_pending_actions(&self, from_index: Option<u64>, limit: Option<u64>) -> Vec<PendingActionJson> {
+        let action_ids = self.pending_actions.keys_as_vector();
+        let pending_actions = self.pending_actions.values_as_vector();
+        page_range(action_ids.len(), from_index, limit)
+            .map(|index| (action_ids.get(index).unwrap(), pending_actions.get(index).unwrap()))
+            .map(|(action_id, pending_action)| PendingActionJson {
+                action_id,
+                action: pending_action.action,
+                queued_at: pending_action.queued_at,
+                executable_at: pending_action.executable_at,
+            })
+            .collect()
+    }
+
+    pub fn get_action_delay(&self) -> U64 {
+        U64(self.action_delay)
+    }
+}
+
+impl StakingContract {
+    fn assert_action_permission(&self, action: &AdminAction) {
+        match action.required_role() {
+            Some(role) => self.assert_role(role),
+            None => self.assert_owner(),
+        }
+    }
+}
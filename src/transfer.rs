@@ -0,0 +1,53 @@
+use crate::*;
+
+pub const STAKE_ON_TRANSFER_GAS: Gas = 25_000_000_000_000;
+pub const STAKE_RESOLVE_TRANSFER_GAS: Gas = 10_000_000_000_000;
+
+// Implemented by contracts receiving stake through `transfer_stake_call`, like NEP-141 `ft_on_transfer`
+// Return the part of the stake to give back to the sender
+#[ext_contract(ext_stake_receiver)]
+pub trait StakeReceiver {
+    fn stake_on_transfer(&mut self, sender_id: AccountId, amount: U128, msg: String) -> PromiseOrValue<U128>;
+}
+
+#[near_bindgen]
+impl StakingContract {
+    // Move staked tokens to another registered account, without unstaking them
+    #[payable]
+    pub fn transfer_stake(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>) {
+        assert_one_yocto();
+        let sender_id = env::predecessor_account_id();
+        self.internal_checked_transfer_stake(&sender_id, &receiver_id, amount.0, memo);
+    }
+
+    // Same as `transfer_stake`, then `stake_on_transfer` is called on the receiver
+    // The sender stays locked until the unused part of the stake is given back
+    #[payable]
+    pub fn transfer_stake_call(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>, msg: String) -> Promise {
+        assert_one_yocto();
+        let sender_id = env::predecessor_account_id();
+        self.internal_checked_transfer_stake(&sender_id, &receiver_id, amount.0, memo);
+        self.internal_lock_account(&sender_id, PendingOperation::TransferStake);
+
+        ext_stake_receiver::stake_on_transfer(
+            sender_id.clone(),
+            amount,
+            msg,
+            &receiver_id,
+            0,
+            STAKE_ON_TRANSFER_GAS
+        ).then(ext_self::stake_resolve_transfer(
+            sender_id,
+            receiver_id,
+            amount,
+            &env::current_account_id(),
+            0,
+            STAKE_RESOLVE_TRANSFER_GAS
+        ))
+    }
+
+    #[private]
+    pub fn stake_resolve_transfer(&mut self, sender_id: AccountId, receiver_id: AccountId, amount: U128) -> U128 {
+        self.internal_resolve_stake_transfer(&sender_id, &receiver_id, amount)
+    }
+}
@@ -1,6 +1,63 @@
 use crate::*;
 
+#[allow(clippy::enum_variant_names)]
 #[derive(BorshDeserialize, BorshSerialize, BorshStorageKey)]
 pub enum StorageKey {
     AccountKey,
-}
\ No newline at end of file
+    VestingKey,
+    RefereesKey,
+    RefereesPerAccountKey { account_hash: Vec<u8> },
+    ReferralEarningKey,
+    AllowlistKey,
+    DenylistKey,
+    RolesKey,
+    RoleMembersKey { role: Role },
+    PendingActionKey,
+    UpgradeHistoryKey,
+    AccountIdsKey,
+    PendingOperationKey,
+    PrepaidStorageKey,
+    StoragePoolUsageKey,
+    PositionKey,
+    PositionsPerOwnerKey,
+    PositionsPerAccountKey { account_hash: Vec<u8> },
+    AccountIndexKey,
+    StorageChargeKey,
+}
+
+pub const BPS_DENOMINATOR: u32 = 10_000;
+
+// Approximations used by the projection views, with blocks of about one second
+pub const BLOCKS_PER_EPOCH: u64 = 43_200;
+pub const BLOCKS_PER_YEAR: u64 = 31_536_000;
+
+// Arguments carried by the `msg` of `ft_transfer_call`
+// An empty message is a plain stake
+#[derive(Deserialize, Serialize, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StakeMsg {
+    pub referrer_id: Option<AccountId>,         // Only recorded when the sender registers itself
+    pub beneficiary_id: Option<AccountId>,      // Account credited with the stake, the sender by default
+    pub action: Option<TransferAction>,         // A stake by default
+}
+
+#[derive(Deserialize, Serialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum TransferAction {
+    Stake,
+    FundAirdrop,    // Treasury managers only, the tokens are credited later by `airdrop_stake`
+}
+
+// Cross-contract operation in flight for an account, which stays locked until the callback
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum PendingOperation {
+    Harvest,
+    Withdraw,
+    ClaimVested,
+    EmergencyWithdraw,
+    TransferStake,
+    TransferPosition,
+    CloseAccount,
+}
@@ -3,15 +3,33 @@ use crate::*;
 #[derive(BorshDeserialize, BorshSerialize)]
 pub enum UpgradableAccount {
     Version1(AccountV1),
+    Version2(AccountV2),
     Current(Account)
 }
 
+impl UpgradableAccount {
+    pub fn version(&self) -> u8 {
+        match self {
+            UpgradableAccount::Version1(_) => 1,
+            UpgradableAccount::Version2(_) => 2,
+            UpgradableAccount::Current(_) => 3,
+        }
+    }
+
+    // Entries written before the current account layout
+    pub fn is_legacy(&self) -> bool {
+        !matches!(self, UpgradableAccount::Current(_))
+    }
+}
+
 impl From<Account> for UpgradableAccount {
     fn from(account: Account) -> Self {
         UpgradableAccount::Current(account)
     }
 }
 
+// Legacy entries keep their last change before the migration, until which they earn at the old rate (see `LegacyRewardRate`)
+// They earn without the membership multiplier, as before, until they are rewritten
 impl From<UpgradableAccount> for Account {
     fn from(upgradable_account: UpgradableAccount) -> Self {
         match upgradable_account {
@@ -19,12 +37,35 @@ impl From<UpgradableAccount> for Account {
             UpgradableAccount::Version1(account) => {
                 Account {
                     stake_balance: account.stake_balance,
-                    pre_reward: account.stake_balance,
+                    pre_reward: account.pre_reward,
+                    last_block_balance_change: account.last_block_balance_change,
+                    unstake_balance: account.unstake_balance,
+                    unstake_start_time: account.unstake_start_time,
+                    unstake_available_epoch: account.unstake_available_epoch,
+                    membership: Membership::Basic,
+                    referrer_id: None,
+                    reward_remainder: 0,
+                    locked_balance: 0,
+                    boost_balance: 0,
+                    reward_per_share_paid: 0,
+                    reward_weight: account.stake_balance,
+                }
+            },
+            UpgradableAccount::Version2(account) => {
+                Account {
+                    stake_balance: account.stake_balance,
+                    pre_reward: account.pre_reward,
                     last_block_balance_change: account.last_block_balance_change,
                     unstake_balance: account.unstake_balance,
                     unstake_start_time: account.unstake_start_time,
                     unstake_available_epoch: account.unstake_available_epoch,
-                    membership: Membership::Basic
+                    membership: account.membership,
+                    referrer_id: None,
+                    reward_remainder: 0,
+                    locked_balance: 0,
+                    boost_balance: 0,
+                    reward_per_share_paid: 0,
+                    reward_weight: account.stake_balance,
                 }
             },
         }
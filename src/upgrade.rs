@@ -0,0 +1,83 @@
+use crate::*;
+use near_sdk::serde_json::json;
+use std::convert::TryInto;
+
+// Raw storage keys, outside of the contract state so that any version can read them
+pub const STAGED_CODE_KEY: &[u8] = b"staged_code";
+pub const STAGED_CODE_INFO_KEY: &[u8] = b"staged_code_info";
+
+pub const MIGRATE_GAS: Gas = 100_000_000_000_000;
+
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StagedCode {
+    pub code_hash: Base58CryptoHash,
+    pub staged_at: Timestamp,
+    pub deployable_at: Timestamp,       // Stakers get the same notice as for admin actions
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct UpgradeRecord {
+    pub code_hash: Base58CryptoHash,
+    pub code_version: String,           // Version of the package of the deployed code
+    pub previous_version: U128,         // Layout versions of the state, before and after the migration
+    pub version: U128,
+    pub timestamp: Timestamp,
+}
+
+#[near_bindgen]
+impl StakingContract {
+    // The code is passed as the raw input of the call, not as JSON
+    pub fn stage_code(&mut self) -> Base58CryptoHash {
+        self.assert_owner();
+        let code = env::input().expect("Code is required");
+        let code_hash: CryptoHash = env::sha256(&code).try_into().unwrap();
+        let staged_code = StagedCode {
+            code_hash: code_hash.into(),
+            staged_at: env::block_timestamp(),
+            deployable_at: env::block_timestamp() + self.action_delay,
+        };
+        env::storage_write(STAGED_CODE_KEY, &code);
+        env::storage_write(STAGED_CODE_INFO_KEY, &staged_code.try_to_vec().unwrap());
+
+        emit_event("code_staged", json!({
+            "code_hash": staged_code.code_hash,
+            "deployable_at": staged_code.deployable_at,
+        }));
+        staged_code.code_hash
+    }
+
+    // Deploy the staged code and migrate the state in the same batch
+    // If the migration fails, the deployment is reverted and the code stays staged
+    // The migration is given the hash of the staged code, so that it only records this upgrade
+    pub fn deploy_staged_code(&mut self) -> Promise {
+        self.assert_owner();
+        let staged_code = get_staged_code().expect("No code is staged");
+        assert!(env::block_timestamp() >= staged_code.deployable_at, "Staged code is still timelocked");
+        let code = env::storage_read(STAGED_CODE_KEY).unwrap();
+
+        Promise::new(env::current_account_id())
+            .deploy_contract(code)
+            .function_call(
+                b"migrate".to_vec(),
+                json!({ "code_hash": staged_code.code_hash }).to_string().into_bytes(),
+                0,
+                MIGRATE_GAS
+            )
+    }
+
+    pub fn get_staged_code(&self) -> Option<StagedCode> {
+        get_staged_code()
+    }
+
+    pub fn get_upgrade_history(&self, from_index: Option<u64>, limit: Option<u64>) -> Vec<UpgradeRecord> {
+        page_range(self.upgrade_history.len(), from_index, limit)
+            .map(|index| self.upgrade_history.get(index).unwrap())
+            .collect()
+    }
+}
+
+pub fn get_staged_code() -> Option<StagedCode> {
+    env::storage_read(STAGED_CODE_INFO_KEY).map(|data| StagedCode::try_from_slice(&data).unwrap())
+}
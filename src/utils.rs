@@ -1,5 +1,15 @@
 use crate::*;
 
+// 256-bit integer for intermediate products, which can exceed `u128`
+// The lints are about the code generated by the macro
+#[allow(clippy::all)]
+mod u256 {
+    uint::construct_uint! {
+        pub struct U256(4);
+    }
+}
+pub use u256::U256;
+
 pub fn assert_at_least_one_yocto() {
     assert!(env::attached_deposit() >= 1, "Require at least 1 yoctoNEAR");
 }
@@ -19,4 +29,47 @@ pub fn refund_deposit(storage_used: StorageUsage) {
     if refund>0 {
         Promise::new(env::predecessor_account_id()).transfer(refund);
     }
-}
\ No newline at end of file
+}
+
+// Values which do not fit are clamped, so that a reward never makes a call fail
+fn saturate(value: U256) -> u128 {
+    if value > U256::from(u128::MAX) {
+        u128::MAX
+    } else {
+        value.as_u128()
+    }
+}
+
+// Indices of a page of a collection of `len` elements, so that only the elements of the page are read
+pub fn page_range(len: u64, from_index: Option<u64>, limit: Option<u64>) -> std::ops::Range<u64> {
+    let from_index = from_index.unwrap_or(0).min(len);
+    let end = limit.map_or(len, |limit| from_index.saturating_add(limit).min(len));
+    from_index..end
+}
+
+// `a * b / denom` without overflow of the intermediate product
+pub fn mul_div(a: u128, b: u128, denom: u128) -> u128 {
+    let result = U256::from(a) * U256::from(b) / U256::from(denom);
+    assert!(result <= U256::from(u128::MAX), "Arithmetic overflow");
+    result.as_u128()
+}
+
+// Scale of the reward per share, so that the reward of a single yocto of stake is not rounded away
+pub const REWARD_PER_SHARE_SCALE: u128 = 1_000_000_000_000_000_000_000_000;
+
+// Growth of the reward per share over `cnt_block` blocks, at `reward_num / reward_denom` per block
+// `remainder` is what was lost to rounding by the previous growth, in units of `1 / reward_denom`
+pub fn reward_per_share_growth(reward_num: u32, reward_denom: u64, cnt_block: BlockHeight, remainder: u64) -> (u128, u64) {
+    let numerator = U256::from(reward_num) * U256::from(cnt_block) * U256::from(REWARD_PER_SHARE_SCALE) + U256::from(remainder);
+    let denom = U256::from(reward_denom);
+    (saturate(numerator / denom), (numerator % denom).as_u64())
+}
+
+// Reward of `stake` for a growth of the reward per share
+// `remainder` is what was lost to rounding by the previous calculation, in units of `1 / REWARD_PER_SHARE_SCALE`,
+// and the new one is returned with the reward
+pub fn calculate_reward(stake: Balance, reward_per_share: u128, remainder: u128) -> (Balance, u128) {
+    let numerator = U256::from(stake) * U256::from(reward_per_share) + U256::from(remainder);
+    let scale = U256::from(REWARD_PER_SHARE_SCALE);
+    (saturate(numerator / scale), (numerator % scale).as_u128())
+}
@@ -0,0 +1,128 @@
+use crate::*;
+
+// When set in `Config`, harvested reward is vested instead of transferred
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy)]
+#[serde(crate = "near_sdk::serde")]
+pub struct VestingConfig {
+    pub cliff_epochs: u64,      // Number of epochs before anything can be claimed
+    pub duration_epochs: u64,   // Number of epochs to release the whole schedule
+}
+
+// Reward vested by one harvest, released linearly from its start to its end epoch
+#[derive(BorshDeserialize, BorshSerialize, Clone)]
+pub struct VestingTranche {
+    pub locked_balance: Balance,        // Reward of the tranche still vesting
+    pub start_epoch: EpochHeight,       // Epoch from which `locked_balance` vests linearly
+    pub cliff_epoch: EpochHeight,       // Nothing is released before this epoch
+    pub end_epoch: EpochHeight,         // `locked_balance` is fully released at this epoch
+}
+
+impl VestingTranche {
+    // Release the vested part of the tranche
+    // The tranche is rebased at `epoch`, so the release rate stays the same
+    fn release(&mut self, epoch: EpochHeight) -> Balance {
+        if epoch < self.cliff_epoch || self.locked_balance == 0 {
+            return 0;
+        }
+
+        let released = if epoch >= self.end_epoch {
+            self.locked_balance
+        } else {
+            mul_div(self.locked_balance, (epoch - self.start_epoch) as Balance, (self.end_epoch - self.start_epoch) as Balance)
+        };
+
+        self.locked_balance -= released;
+        self.start_epoch = epoch;
+        released
+    }
+}
+
+// Each harvest vests on its own tranche, so harvesting never delays the reward already vesting
+#[derive(BorshDeserialize, BorshSerialize, Clone)]
+pub struct VestingSchedule {
+    pub unlocked_balance: Balance,      // Released reward, ready to claim
+    pub tranches: Vec<VestingTranche>,  // Tranches still vesting, by start epoch
+}
+
+impl VestingSchedule {
+    pub fn new() -> Self {
+        VestingSchedule {
+            unlocked_balance: 0,
+            tranches: vec![],
+        }
+    }
+
+    // Reward still vesting
+    pub fn locked_balance(&self) -> Balance {
+        self.tranches.iter().map(|tranche| tranche.locked_balance).sum()
+    }
+
+    // Epoch at which the whole schedule is released
+    pub fn end_epoch(&self) -> Option<EpochHeight> {
+        self.tranches.iter().map(|tranche| tranche.end_epoch).max()
+    }
+
+    // Move the vested part of every tranche to the unlocked balance, and drop the released tranches
+    pub fn release(&mut self, epoch: EpochHeight) {
+        for tranche in self.tranches.iter_mut() {
+            self.unlocked_balance += tranche.release(epoch);
+        }
+        self.tranches.retain(|tranche| tranche.locked_balance > 0);
+    }
+
+    // Add harvested reward to the schedule, on a new tranche
+    pub fn add(&mut self, amount: Balance, epoch: EpochHeight, config: &VestingConfig) {
+        self.add_tranche(amount, epoch, epoch + config.cliff_epochs, epoch + config.duration_epochs);
+    }
+
+    // Harvests of the same epoch share their tranche
+    pub fn add_tranche(&mut self, amount: Balance, epoch: EpochHeight, cliff_epoch: EpochHeight, end_epoch: EpochHeight) {
+        self.release(epoch);
+        if end_epoch <= epoch {
+            self.unlocked_balance += amount;
+            return;
+        }
+
+        match self.tranches.iter_mut().find(|tranche| tranche.start_epoch == epoch && tranche.cliff_epoch == cliff_epoch && tranche.end_epoch == end_epoch) {
+            Some(tranche) => tranche.locked_balance += amount,
+            None => self.tranches.push(VestingTranche { locked_balance: amount, start_epoch: epoch, cliff_epoch, end_epoch }),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct VestingTrancheJson {
+    pub locked_balance: U128,
+    pub cliff_epoch: EpochHeight,
+    pub end_epoch: EpochHeight,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct VestingJson {
+    pub account_id: AccountId,
+    pub locked_balance: U128,
+    pub claimable_balance: U128,
+    pub end_epoch: Option<EpochHeight>,
+    pub tranches: Vec<VestingTrancheJson>,
+    pub current_epoch: EpochHeight,
+}
+
+impl VestingJson {
+    pub fn from(account_id: AccountId, mut schedule: VestingSchedule) -> Self {
+        schedule.release(env::epoch_height());
+        VestingJson {
+            account_id,
+            locked_balance: U128(schedule.locked_balance()),
+            claimable_balance: U128(schedule.unlocked_balance),
+            end_epoch: schedule.end_epoch(),
+            tranches: schedule.tranches.iter().map(|tranche| VestingTrancheJson {
+                locked_balance: U128(tranche.locked_balance),
+                cliff_epoch: tranche.cliff_epoch,
+                end_epoch: tranche.end_epoch,
+            }).collect(),
+            current_epoch: env::epoch_height(),
+        }
+    }
+}
@@ -0,0 +1,324 @@
+// A small runtime for end-to-end scenarios of the staking contract against the mock fungible token
+//
+// This is not near-sdk-sim nor a sandbox, which are not available to this workspace: both contracts are
+// compiled natively and run on the `MockedBlockchain` of near-sdk, and `Simulation` plays the runtime.
+// Every call is executed like a receipt: a panic makes it fail and reverts its state, and the receipts
+// it creates are routed to the right contract, with the results of the receipts they depend on as promise results.
+// Gas, NEAR balances and storage staking are not simulated, the unit tests cover them where they matter.
+// A method becomes callable by adding its line to `Simulation::dispatch`.
+
+use std::collections::HashMap;
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+
+use mock_ft::MockFungibleToken;
+use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::de::DeserializeOwned;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json::{self, json, Value};
+use near_sdk::test_utils::{get_created_receipts, VMContextBuilder};
+use near_sdk::{env, AccountId, Balance, MockedBlockchain, Promise, PromiseOrValue, PromiseResult};
+use staking_contract::{Account, AccountJson, Config, FungibleTokenReceiver, PoolJson, StakingContract, StorageBalance, StorageCharge};
+
+pub const OWNER: &str = "owner.near";
+pub const STAKING: &str = "staking.near";
+pub const FT: &str = "ft.near";
+pub const ALICE: &str = "alice.near";
+pub const BOB: &str = "bob.near";
+// Receiver of `transfer_stake_call`, of the receipt token and of the position tokens, which gives back what the message says
+pub const RECEIVER: &str = "receiver.near";
+
+pub const ONE_TOKEN: Balance = 1_000_000_000_000_000_000_000_000;
+pub const ONE_NEAR: Balance = 1_000_000_000_000_000_000_000_000;
+pub const TOTAL_SUPPLY: Balance = 1_000_000 * ONE_TOKEN;
+pub const REWARD_RESERVE: Balance = 100_000 * ONE_TOKEN;
+
+pub fn is_success(result: &PromiseResult) -> bool {
+    matches!(result, PromiseResult::Successful(_))
+}
+
+// Whether the call succeeded with the given return value
+pub fn returned<T: Serialize>(result: &PromiseResult, value: T) -> bool {
+    matches!(result, PromiseResult::Successful(result) if *result == serde_json::to_vec(&value).unwrap())
+}
+
+// Call the method with the arguments of the same names, read from the JSON arguments of the receipt
+macro_rules! call {
+    ($contract:expr, $args:expr, $method:ident($($name:ident),*)) => {
+        $contract.$method($(arg($args, stringify!($name))),*).into_outcome()
+    };
+}
+
+pub struct Simulation {
+    pub staking: StakingContract,
+    pub ft: MockFungibleToken,
+    block_index: u64,
+    epoch_height: u64,
+}
+
+impl Simulation {
+    // The owner holds the supply, and funds the reward reserve of the staking contract
+    pub fn new() -> Self {
+        Self::with_config(Config::default())
+    }
+
+    pub fn with_config(config: Config) -> Self {
+        set_context(FT, OWNER, 0, 0, 0, vec![], HashMap::new());
+        let ft = MockFungibleToken::new(OWNER.to_string(), U128(TOTAL_SUPPLY));
+        set_context(STAKING, OWNER, 0, 0, 0, vec![], take_storage());
+        let staking = StakingContract::new(OWNER.to_string(), FT.to_string(), config);
+
+        let mut sim = Simulation { staking, ft, block_index: 0, epoch_height: 0 };
+        assert!(is_success(&sim.call(OWNER, FT, "storage_deposit", json!({ "account_id": STAKING }), 0)));
+        assert!(is_success(&sim.call(OWNER, FT, "ft_transfer", json!({ "receiver_id": STAKING, "amount": U128(REWARD_RESERVE) }), 1)));
+        sim
+    }
+
+    // Register the user on both contracts and give it some tokens
+    pub fn create_user(&mut self, user: &str, amount: Balance) {
+        assert!(is_success(&self.call(user, FT, "storage_deposit", json!({}), 0)));
+        assert!(is_success(&self.call(user, STAKING, "storage_deposit", json!({}), ONE_NEAR)));
+        assert!(is_success(&self.call(OWNER, FT, "ft_transfer", json!({ "receiver_id": user, "amount": U128(amount) }), 1)));
+    }
+
+    pub fn stake(&mut self, user: &str, amount: Balance) -> PromiseResult {
+        self.stake_with_msg(user, amount, "")
+    }
+
+    pub fn stake_with_msg(&mut self, user: &str, amount: Balance, msg: &str) -> PromiseResult {
+        self.call(user, FT, "ft_transfer_call", json!({ "receiver_id": STAKING, "amount": U128(amount), "msg": msg }), 1)
+    }
+
+    pub fn advance(&mut self, num_blocks: u64, num_epochs: u64) {
+        self.block_index += num_blocks;
+        self.epoch_height += num_epochs;
+    }
+
+    // Read the state of the staking contract at the current block
+    pub fn view<T>(&self, f: impl FnOnce(&StakingContract) -> T) -> T {
+        set_context(STAKING, OWNER, 0, self.block_index, self.epoch_height, vec![], take_storage());
+        f(&self.staking)
+    }
+
+    pub fn ft_balance_of(&self, account_id: &str) -> Balance {
+        set_context(FT, OWNER, 0, self.block_index, self.epoch_height, vec![], take_storage());
+        self.ft.ft_balance_of(account_id.to_string()).0
+    }
+
+    pub fn account_info(&self, account_id: &str) -> AccountJson {
+        self.view(|staking| staking.get_account_info(&account_id.to_string()))
+    }
+
+    pub fn pool_info(&self) -> PoolJson {
+        self.view(|staking| staking.get_pool_info())
+    }
+
+    pub fn call(&mut self, predecessor: &str, receiver: &str, method: &str, args: Value, deposit: Balance) -> PromiseResult {
+        self.execute(predecessor, receiver, method, &args.to_string(), deposit, vec![])
+    }
+
+    fn execute(&mut self, predecessor: &str, receiver: &str, method: &str, args: &str, deposit: Balance, promise_results: Vec<PromiseResult>) -> PromiseResult {
+        // A failed call leaves no trace, like on chain
+        let storage = take_storage();
+        let snapshot = (storage.clone(), self.staking.try_to_vec().unwrap(), self.ft.try_to_vec().unwrap());
+        set_context(receiver, predecessor, deposit, self.block_index, self.epoch_height, promise_results, storage);
+        let outcome = match catch_unwind(AssertUnwindSafe(|| self.dispatch(receiver, method, args))) {
+            Ok(outcome) => outcome,
+            Err(err) if err.downcast_ref::<String>().is_some_and(|err| err.starts_with("Unsupported call")) => resume_unwind(err),
+            Err(_) => {
+                let (storage, staking, ft) = snapshot;
+                self.staking = StakingContract::try_from_slice(&staking).unwrap();
+                self.ft = MockFungibleToken::try_from_slice(&ft).unwrap();
+                take_storage();
+                set_context(receiver, predecessor, 0, self.block_index, self.epoch_height, vec![], storage);
+                return PromiseResult::Failed;
+            },
+        };
+
+        let receipts: Vec<ReceiptView> = serde_json::from_str(&serde_json::to_string(&get_created_receipts()).unwrap()).unwrap();
+        let last_result = self.run_receipts(receiver, receipts);
+        match outcome {
+            Outcome::Value(value) => PromiseResult::Successful(value),
+            Outcome::Promise => last_result.expect("Promise without receipts"),
+        }
+    }
+
+    fn run_receipts(&mut self, predecessor: &str, receipts: Vec<ReceiptView>) -> Option<PromiseResult> {
+        let mut results: Vec<PromiseResult> = vec![];
+        for receipt in receipts {
+            let mut result = PromiseResult::Successful(vec![]);
+            for action in receipt.actions {
+                // NEAR transfers (like storage refunds) always succeed
+                if let Some(call) = action.get("FunctionCall") {
+                    let promise_results = receipt.receipt_indices.iter().map(|index| clone_result(&results[*index])).collect();
+                    let (method, args) = (call.method_name.as_ref().unwrap(), call.args.as_ref().unwrap());
+                    result = self.execute(predecessor, &receipt.receiver_id, method, args, call.deposit, promise_results);
+                }
+            }
+            results.push(result);
+        }
+        results.pop()
+    }
+
+    fn dispatch(&mut self, receiver: &str, method: &str, args: &str) -> Outcome {
+        match (receiver, method) {
+            (FT, "storage_deposit") => call!(self.ft, args, storage_deposit(account_id)),
+            (FT, "storage_unregister") => call!(self.ft, args, storage_unregister()),
+            (FT, "ft_transfer") => call!(self.ft, args, ft_transfer(receiver_id, amount, memo)),
+            (FT, "ft_transfer_call") => call!(self.ft, args, ft_transfer_call(receiver_id, amount, memo, msg)),
+            (FT, "ft_balance_of") => call!(self.ft, args, ft_balance_of(account_id)),
+            (FT, "ft_resolve_transfer") => call!(self.ft, args, ft_resolve_transfer(sender_id, receiver_id, amount)),
+            (STAKING, "storage_deposit") => call!(self.staking, args, storage_deposit(account_id, registration_only, referrer_id)),
+            (STAKING, "ft_on_transfer") => call!(self.staking, args, ft_on_transfer(sender_id, amount, msg)),
+            (STAKING, "harvest") => call!(self.staking, args, harvest()),
+            (STAKING, "ft_harvest_callback") => call!(self.staking, args, ft_harvest_callback(account_id, amount)),
+            (STAKING, "unstake") => call!(self.staking, args, unstake(amount)),
+            (STAKING, "withdraw") => call!(self.staking, args, withdraw()),
+            (STAKING, "ft_withdraw_callback") => {
+                let args: WithdrawCallbackArgs = serde_json::from_str(args).unwrap();
+                self.staking.ft_withdraw_callback(args.account_id, args.old_account).into_outcome()
+            },
+            (STAKING, "check_invariants") => call!(self.staking, args, check_invariants(limit)),
+            (STAKING, "ft_solvency_callback") => call!(self.staking, args, ft_solvency_callback(liabilities)),
+            (STAKING, "transfer_stake_call") => call!(self.staking, args, transfer_stake_call(receiver_id, amount, memo, msg)),
+            (STAKING, "stake_resolve_transfer") => call!(self.staking, args, stake_resolve_transfer(sender_id, receiver_id, amount)),
+            (STAKING, "ft_transfer_call") => call!(self.staking, args, ft_transfer_call(receiver_id, amount, memo, msg)),
+            (STAKING, "ft_resolve_transfer") => call!(self.staking, args, ft_resolve_transfer(sender_id, receiver_id, amount)),
+            (STAKING, "prepay_storage") => call!(self.staking, args, prepay_storage()),
+            (STAKING, "fund_storage_pool") => call!(self.staking, args, fund_storage_pool()),
+            (STAKING, "lock_stake") => call!(self.staking, args, lock_stake(amount, lock_epochs)),
+            (STAKING, "nft_transfer_call") => call!(self.staking, args, nft_transfer_call(receiver_id, token_id, approval_id, memo, msg)),
+            (STAKING, "nft_resolve_transfer") => call!(self.staking, args, nft_resolve_transfer(previous_owner_id, receiver_id, token_id, approved_account_ids)),
+            (STAKING, "airdrop_stake") => call!(self.staking, args, airdrop_stake(credits, from_index)),
+            (STAKING, "close_account") => call!(self.staking, args, close_account()),
+            (STAKING, "ft_close_account_callback") => {
+                let args: CloseAccountCallbackArgs = serde_json::from_str(args).unwrap();
+                self.staking.ft_close_account_callback(args.account_id, args.old_account, args.vested, args.storage_charge).into_outcome()
+            },
+            (RECEIVER, "ft_on_transfer" | "stake_on_transfer") => U128(arg::<String>(args, "msg").parse().unwrap()).into_outcome(),
+            (RECEIVER, "nft_on_transfer") => arg::<String>(args, "msg").parse::<bool>().unwrap().into_outcome(),
+            _ => panic!("Unsupported call {}::{}", receiver, method),
+        }
+    }
+}
+
+fn take_storage() -> HashMap<Vec<u8>, Vec<u8>> {
+    match env::take_blockchain_interface() {
+        Some(mut blockchain) => blockchain.as_mut_mocked_blockchain().unwrap().take_storage(),
+        None => Default::default(),
+    }
+}
+
+fn set_context(receiver: &str, predecessor: &str, deposit: Balance, block_index: u64, epoch_height: u64, promise_results: Vec<PromiseResult>, storage: HashMap<Vec<u8>, Vec<u8>>) {
+    let mut builder = VMContextBuilder::new();
+    builder.context.current_account_id = receiver.to_string();
+    builder.context.predecessor_account_id = predecessor.to_string();
+    builder.context.signer_account_id = predecessor.to_string();
+    builder.context.attached_deposit = deposit;
+    builder.context.account_balance = 1_000 * ONE_NEAR;
+    builder.context.block_index = block_index;
+    builder.context.epoch_height = epoch_height;
+    env::set_blockchain_interface(Box::new(MockedBlockchain::new(
+        builder.build(),
+        Default::default(),
+        Default::default(),
+        promise_results,
+        storage,
+        Default::default(),
+        None,
+    )));
+}
+
+fn clone_result(result: &PromiseResult) -> PromiseResult {
+    match result {
+        PromiseResult::NotReady => PromiseResult::NotReady,
+        PromiseResult::Successful(value) => PromiseResult::Successful(value.clone()),
+        PromiseResult::Failed => PromiseResult::Failed,
+    }
+}
+
+// What a method returns to the runtime: a value, or the result of the last receipt it created
+enum Outcome {
+    Value(Vec<u8>),
+    Promise,
+}
+
+trait IntoOutcome {
+    fn into_outcome(self) -> Outcome;
+}
+
+// Like on chain, a method without a return value returns nothing
+impl IntoOutcome for () {
+    fn into_outcome(self) -> Outcome {
+        Outcome::Value(vec![])
+    }
+}
+
+impl IntoOutcome for Promise {
+    fn into_outcome(self) -> Outcome {
+        Outcome::Promise
+    }
+}
+
+impl<T: Serialize> IntoOutcome for PromiseOrValue<T> {
+    fn into_outcome(self) -> Outcome {
+        match self {
+            PromiseOrValue::Promise(_) => Outcome::Promise,
+            PromiseOrValue::Value(value) => Outcome::Value(serde_json::to_vec(&value).unwrap()),
+        }
+    }
+}
+
+macro_rules! impl_value_outcome {
+    ($($ty:ty),*) => {
+        $(impl IntoOutcome for $ty {
+            fn into_outcome(self) -> Outcome {
+                Outcome::Value(serde_json::to_vec(&self).unwrap())
+            }
+        })*
+    };
+}
+
+impl_value_outcome!(bool, u64, String, U128, StorageBalance);
+
+// Plain `u128` amounts (like accounts passed to callbacks) do not fit in a JSON `Value`,
+// so arguments are kept as a string and parsed into their expected type
+fn arg<T: DeserializeOwned>(args: &str, key: &str) -> T {
+    let args: Value = serde_json::from_str(args).unwrap();
+    serde_json::from_value(args[key].clone()).unwrap()
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+struct WithdrawCallbackArgs {
+    account_id: AccountId,
+    old_account: Account,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+struct CloseAccountCallbackArgs {
+    account_id: AccountId,
+    old_account: Account,
+    vested: U128,
+    storage_charge: StorageCharge,
+}
+
+// Mirror of the receipts recorded by the mocked blockchain
+// Deposits do not fit in a JSON `Value`, so they are read into typed fields
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+struct ReceiptView {
+    receipt_indices: Vec<usize>,
+    receiver_id: String,
+    actions: Vec<HashMap<String, ActionView>>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+struct ActionView {
+    method_name: Option<String>,
+    args: Option<String>,
+    #[serde(default)]
+    deposit: Balance,
+}
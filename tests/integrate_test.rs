@@ -0,0 +1,352 @@
+// End-to-end scenarios of the staking contract against the mock fungible token, run by the `Simulation` of `common`
+
+mod common;
+
+use common::*;
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::json;
+use staking_contract::{AirdropCredit, AutoRegisterConfig, Config, PositionConfig};
+
+#[test]
+fn test_stake_harvest_unstake_withdraw() {
+    let mut sim = Simulation::new();
+    sim.create_user(ALICE, 1_000 * ONE_TOKEN);
+
+    // Stake through `ft_transfer_call`, nothing is refunded
+    assert!(is_success(&sim.stake(ALICE, 1_000 * ONE_TOKEN)));
+    assert_eq!(sim.ft_balance_of(ALICE), 0);
+    assert_eq!(sim.ft_balance_of(STAKING), REWARD_RESERVE + 1_000 * ONE_TOKEN);
+    let account = sim.account_info(ALICE);
+    assert_eq!(account.stake_balance, 1_000 * ONE_TOKEN);
+    assert_eq!(account.reward, 0);
+
+    // Accrue reward over blocks
+    sim.advance(1_000, 0);
+    let reward = 715 * 1_000 * ONE_TOKEN * 1_000 / 10u128.pow(9);
+    assert_eq!(sim.account_info(ALICE).reward, reward);
+
+    assert!(is_success(&sim.call(ALICE, STAKING, "harvest", json!({}), 1)));
+    assert_eq!(sim.ft_balance_of(ALICE), reward);
+    assert_eq!(sim.ft_balance_of(STAKING), REWARD_RESERVE + 1_000 * ONE_TOKEN - reward);
+    assert_eq!(sim.account_info(ALICE).reward, 0);
+
+    // Unstaked tokens are locked until the next epoch
+    assert!(is_success(&sim.call(ALICE, STAKING, "unstake", json!({ "amount": U128(1_000 * ONE_TOKEN) }), 1)));
+    let account = sim.account_info(ALICE);
+    assert_eq!(account.stake_balance, 0);
+    assert_eq!(account.unstake_balance, 1_000 * ONE_TOKEN);
+    assert!(!account.can_withdraw);
+    assert!(!is_success(&sim.call(ALICE, STAKING, "withdraw", json!({}), 1)));
+
+    sim.advance(100, 1);
+    assert!(sim.account_info(ALICE).can_withdraw);
+    assert!(is_success(&sim.call(ALICE, STAKING, "withdraw", json!({}), 1)));
+    assert_eq!(sim.ft_balance_of(ALICE), 1_000 * ONE_TOKEN + reward);
+    assert_eq!(sim.ft_balance_of(STAKING), REWARD_RESERVE - reward);
+    let account = sim.account_info(ALICE);
+    assert_eq!(account.unstake_balance, 0);
+    assert_eq!(account.stake_balance, 0);
+
+    let pool = sim.pool_info();
+    assert_eq!(pool.total_paid_reward.0, reward);
+    assert_eq!(pool.total_unstaking.0, 0);
+    assert_eq!(pool.total_withdrawn.0, 1_000 * ONE_TOKEN);
+    assert_eq!(pool.ft_contract_id, FT);
+    assert_eq!(pool.current_epoch, 1);
+}
+
+#[test]
+fn test_harvest_pays_only_the_account_reward() {
+    let mut sim = Simulation::new();
+    sim.create_user(ALICE, 1_000 * ONE_TOKEN);
+    sim.create_user(BOB, 1_000 * ONE_TOKEN);
+
+    // Bob's stake settles the reward of the pool, which must not leak into Alice's harvest
+    assert!(is_success(&sim.stake(ALICE, 1_000 * ONE_TOKEN)));
+    sim.advance(1_000, 0);
+    assert!(is_success(&sim.stake(BOB, 1_000 * ONE_TOKEN)));
+    sim.advance(1_000, 0);
+
+    let reward = 715 * 1_000 * ONE_TOKEN * 2_000 / 10u128.pow(9);
+    assert_eq!(sim.account_info(ALICE).reward, reward);
+    assert!(is_success(&sim.call(ALICE, STAKING, "harvest", json!({}), 1)));
+    assert_eq!(sim.ft_balance_of(ALICE), reward);
+    assert_eq!(sim.account_info(ALICE).reward, 0);
+    assert_eq!(sim.account_info(BOB).reward, 715 * 1_000 * ONE_TOKEN * 1_000 / 10u128.pow(9));
+}
+
+#[test]
+fn test_stake_without_registration_is_refunded() {
+    let mut sim = Simulation::new();
+    assert!(is_success(&sim.call(BOB, FT, "storage_deposit", json!({}), 0)));
+    assert!(is_success(&sim.call(OWNER, FT, "ft_transfer", json!({ "receiver_id": BOB, "amount": U128(100 * ONE_TOKEN) }), 1)));
+
+    // `ft_on_transfer` fails, so the token contract refunds the whole transfer
+    let result = sim.stake(BOB, 100 * ONE_TOKEN);
+    assert!(returned(&result, U128(0)));
+    assert_eq!(sim.ft_balance_of(BOB), 100 * ONE_TOKEN);
+    assert_eq!(sim.ft_balance_of(STAKING), REWARD_RESERVE);
+    assert!(!sim.view(|staking| staking.exist_account(BOB.to_string())));
+    assert_eq!(sim.pool_info().total_stake_balance.0, 0);
+}
+
+#[test]
+fn test_withdraw_rollback_on_ft_failure() {
+    let mut sim = Simulation::new();
+    sim.create_user(ALICE, 500 * ONE_TOKEN);
+    assert!(is_success(&sim.stake(ALICE, 500 * ONE_TOKEN)));
+    assert!(is_success(&sim.call(ALICE, STAKING, "unstake", json!({ "amount": U128(500 * ONE_TOKEN) }), 1)));
+    sim.advance(100, 1);
+
+    // Alice cannot receive tokens anymore, so the transfer fails and the account is restored
+    assert!(is_success(&sim.call(ALICE, FT, "storage_unregister", json!({}), 0)));
+    let result = sim.call(ALICE, STAKING, "withdraw", json!({}), 1);
+    assert!(returned(&result, U128(0)));
+    assert_eq!(sim.ft_balance_of(STAKING), REWARD_RESERVE + 500 * ONE_TOKEN);
+    let account = sim.account_info(ALICE);
+    assert_eq!(account.unstake_balance, 500 * ONE_TOKEN);
+    assert!(account.can_withdraw);
+    let pool = sim.pool_info();
+    assert_eq!(pool.total_unstaking.0, 500 * ONE_TOKEN);
+    assert_eq!(pool.total_withdrawn.0, 0);
+
+    // Once registered again, the withdrawal goes through
+    assert!(is_success(&sim.call(ALICE, FT, "storage_deposit", json!({}), 0)));
+    assert!(is_success(&sim.call(ALICE, STAKING, "withdraw", json!({}), 1)));
+    assert_eq!(sim.ft_balance_of(ALICE), 500 * ONE_TOKEN);
+    assert_eq!(sim.ft_balance_of(STAKING), REWARD_RESERVE);
+    assert_eq!(sim.account_info(ALICE).unstake_balance, 0);
+    let pool = sim.pool_info();
+    assert_eq!(pool.total_unstaking.0, 0);
+    assert_eq!(pool.total_withdrawn.0, 500 * ONE_TOKEN);
+}
+
+#[test]
+fn test_check_invariants_against_token_balance() {
+    let mut sim = Simulation::new();
+    sim.create_user(ALICE, 1_000 * ONE_TOKEN);
+    sim.create_user(BOB, 1_000 * ONE_TOKEN);
+    assert!(is_success(&sim.stake(ALICE, 1_000 * ONE_TOKEN)));
+    assert!(is_success(&sim.stake(BOB, 400 * ONE_TOKEN)));
+    sim.advance(1_000, 0);
+    assert!(is_success(&sim.call(BOB, STAKING, "unstake", json!({ "amount": U128(100 * ONE_TOKEN) }), 1)));
+
+    // The reserve covers the pending reward, the difference is the surplus
+    let reward = 715 * 1_400 * ONE_TOKEN * 1_000 / 10u128.pow(9);
+    let result = sim.call(OWNER, STAKING, "check_invariants", json!({}), 0);
+    let surplus = REWARD_RESERVE - reward;
+    assert!(returned(&result, U128(surplus)));
+
+    // Reward accrued beyond the reserve makes the contract insolvent
+    let reward_per_block = 715 * 1_300 * ONE_TOKEN / 10u128.pow(9);
+    sim.advance((REWARD_RESERVE / reward_per_block) as u64 + 1, 0);
+    assert!(!is_success(&sim.call(OWNER, STAKING, "check_invariants", json!({}), 0)));
+}
+
+#[test]
+fn test_harvest_rollback_on_ft_failure() {
+    let mut sim = Simulation::new();
+    sim.create_user(ALICE, 1_000 * ONE_TOKEN);
+    assert!(is_success(&sim.stake(ALICE, 1_000 * ONE_TOKEN)));
+    sim.advance(1_000, 0);
+    let reward = 715 * 1_000 * ONE_TOKEN * 1_000 / 10u128.pow(9);
+
+    // The transfer fails, so the deducted reward is restored and nothing is counted as paid
+    assert!(is_success(&sim.call(ALICE, FT, "storage_unregister", json!({}), 0)));
+    let result = sim.call(ALICE, STAKING, "harvest", json!({}), 1);
+    assert!(returned(&result, U128(0)));
+    assert_eq!(sim.account_info(ALICE).reward, reward);
+    assert_eq!(sim.pool_info().total_paid_reward.0, 0);
+    assert_eq!(sim.ft_balance_of(STAKING), REWARD_RESERVE + 1_000 * ONE_TOKEN);
+
+    assert!(is_success(&sim.call(ALICE, FT, "storage_deposit", json!({}), 0)));
+    assert!(is_success(&sim.call(ALICE, STAKING, "harvest", json!({}), 1)));
+    assert_eq!(sim.ft_balance_of(ALICE), reward);
+    assert_eq!(sim.account_info(ALICE).reward, 0);
+    assert_eq!(sim.pool_info().total_paid_reward.0, reward);
+}
+
+#[test]
+fn test_stake_on_behalf_of_beneficiary() {
+    let mut sim = Simulation::new();
+    sim.create_user(ALICE, 1_000 * ONE_TOKEN);
+    let msg = json!({ "beneficiary_id": BOB }).to_string();
+
+    // Bob is not registered, and Alice did not prepay storage: the transfer is refunded
+    let result = sim.stake_with_msg(ALICE, 300 * ONE_TOKEN, &msg);
+    assert!(returned(&result, U128(0)));
+    assert_eq!(sim.ft_balance_of(ALICE), 1_000 * ONE_TOKEN);
+
+    assert!(is_success(&sim.call(ALICE, STAKING, "prepay_storage", json!({}), ONE_NEAR)));
+    assert!(is_success(&sim.stake_with_msg(ALICE, 300 * ONE_TOKEN, &msg)));
+    assert_eq!(sim.ft_balance_of(ALICE), 700 * ONE_TOKEN);
+    assert_eq!(sim.account_info(BOB).stake_balance, 300 * ONE_TOKEN);
+    assert_eq!(sim.account_info(ALICE).stake_balance, 0);
+    assert!(sim.view(|staking| staking.get_prepaid_storage(ALICE.to_string())).0 < ONE_NEAR);
+
+    // Once registered, more can be staked for Bob without using the prepaid storage
+    let prepaid = sim.view(|staking| staking.get_prepaid_storage(ALICE.to_string()));
+    assert!(is_success(&sim.stake_with_msg(ALICE, 200 * ONE_TOKEN, &msg)));
+    assert_eq!(sim.account_info(BOB).stake_balance, 500 * ONE_TOKEN);
+    assert_eq!(sim.view(|staking| staking.get_prepaid_storage(ALICE.to_string())), prepaid);
+}
+
+#[test]
+fn test_auto_register_from_storage_pool() {
+    let storage_cost = ONE_NEAR / 100;
+    let mut sim = Simulation::with_config(Config {
+        auto_register: Some(AutoRegisterConfig { max_storage_per_account: U128(storage_cost) }),
+        ..Config::default()
+    });
+    for user in [ALICE, BOB] {
+        assert!(is_success(&sim.call(user, FT, "storage_deposit", json!({}), 0)));
+        assert!(is_success(&sim.call(OWNER, FT, "ft_transfer", json!({ "receiver_id": user, "amount": U128(100 * ONE_TOKEN) }), 1)));
+    }
+
+    // The storage pool is empty, so the transfer is refunded
+    let result = sim.stake(ALICE, 100 * ONE_TOKEN);
+    assert!(returned(&result, U128(0)));
+    assert_eq!(sim.ft_balance_of(ALICE), 100 * ONE_TOKEN);
+
+    // Once funded, Alice is registered on her first stake
+    assert!(is_success(&sim.call(OWNER, STAKING, "fund_storage_pool", json!({}), ONE_NEAR)));
+    assert!(is_success(&sim.stake(ALICE, 100 * ONE_TOKEN)));
+    assert_eq!(sim.account_info(ALICE).stake_balance, 100 * ONE_TOKEN);
+    let used = sim.view(|staking| staking.get_storage_pool_usage(ALICE.to_string())).0;
+    assert!(used > 0 && used <= storage_cost);
+    assert_eq!(sim.view(|staking| staking.get_storage_pool()).0, ONE_NEAR - used);
+    assert_eq!(sim.ft_balance_of(STAKING), REWARD_RESERVE + 100 * ONE_TOKEN);
+}
+
+#[test]
+fn test_transfer_stake_call_gives_back_unused_stake() {
+    let mut sim = Simulation::new();
+    sim.create_user(ALICE, 1_000 * ONE_TOKEN);
+    assert!(is_success(&sim.call(RECEIVER, STAKING, "storage_deposit", json!({}), ONE_NEAR)));
+    assert!(is_success(&sim.stake(ALICE, 1_000 * ONE_TOKEN)));
+
+    // The receiver keeps 600 of the 1000 transferred
+    let unused = (400 * ONE_TOKEN).to_string();
+    let args = json!({ "receiver_id": RECEIVER, "amount": U128(1_000 * ONE_TOKEN), "msg": unused });
+    let result = sim.call(ALICE, STAKING, "transfer_stake_call", args, 1);
+    assert!(returned(&result, U128(600 * ONE_TOKEN)));
+    assert_eq!(sim.account_info(ALICE).stake_balance, 400 * ONE_TOKEN);
+    assert_eq!(sim.account_info(RECEIVER).stake_balance, 600 * ONE_TOKEN);
+    let pool = sim.pool_info();
+    assert_eq!(pool.total_stake_balance.0, 1_000 * ONE_TOKEN);
+    assert_eq!(pool.total_staker.0, 2);
+
+    // Alice is unlocked once the transfer is resolved
+    assert!(is_success(&sim.call(ALICE, STAKING, "unstake", json!({ "amount": U128(400 * ONE_TOKEN) }), 1)));
+    assert_eq!(sim.pool_info().total_staker.0, 1);
+}
+
+#[test]
+fn test_receipt_token_transfer_call() {
+    let mut sim = Simulation::new();
+    sim.create_user(ALICE, 1_000 * ONE_TOKEN);
+    assert!(is_success(&sim.call(RECEIVER, STAKING, "storage_deposit", json!({}), ONE_NEAR)));
+    assert!(is_success(&sim.stake(ALICE, 1_000 * ONE_TOKEN)));
+
+    // The receiver uses all the receipt tokens, and earns the reward of the stake from then on
+    let args = json!({ "receiver_id": RECEIVER, "amount": U128(250 * ONE_TOKEN), "msg": "0" });
+    let result = sim.call(ALICE, STAKING, "ft_transfer_call", args, 1);
+    assert!(returned(&result, U128(250 * ONE_TOKEN)));
+    assert_eq!(sim.view(|staking| staking.ft_balance_of(ALICE.to_string())).0, 750 * ONE_TOKEN);
+    assert_eq!(sim.view(|staking| staking.ft_balance_of(RECEIVER.to_string())).0, 250 * ONE_TOKEN);
+    assert_eq!(sim.view(|staking| staking.ft_total_supply()).0, 1_000 * ONE_TOKEN);
+
+    sim.advance(1_000, 0);
+    assert_eq!(sim.account_info(RECEIVER).reward, 715 * 250 * ONE_TOKEN * 1_000 / 10u128.pow(9));
+}
+
+#[test]
+fn test_locked_position_transfer_call() {
+    let mut sim = Simulation::with_config(Config {
+        position: Some(PositionConfig { min_lock_epochs: 1, max_lock_epochs: 10, max_boost_bps: 5_000 }),
+        ..Config::default()
+    });
+    sim.create_user(ALICE, 1_000 * ONE_TOKEN);
+    assert!(is_success(&sim.call(RECEIVER, STAKING, "storage_deposit", json!({}), ONE_NEAR)));
+    assert!(is_success(&sim.stake(ALICE, 1_000 * ONE_TOKEN)));
+    assert!(is_success(&sim.call(ALICE, STAKING, "lock_stake", json!({ "amount": U128(600 * ONE_TOKEN), "lock_epochs": 5 }), ONE_NEAR)));
+
+    // The receiver gives the token back, the position and its stake return to the sender
+    let args = json!({ "receiver_id": RECEIVER, "token_id": "0", "msg": "true" });
+    let result = sim.call(ALICE, STAKING, "nft_transfer_call", args, 1);
+    assert!(returned(&result, false));
+    let alice = sim.account_info(ALICE);
+    assert_eq!((alice.stake_balance, alice.locked_balance, alice.boost_balance), (1_000 * ONE_TOKEN, 600 * ONE_TOKEN, 150 * ONE_TOKEN));
+    assert_eq!(sim.account_info(RECEIVER).stake_balance, 0);
+
+    // The receiver keeps it
+    let args = json!({ "receiver_id": RECEIVER, "token_id": "0", "msg": "false" });
+    let result = sim.call(ALICE, STAKING, "nft_transfer_call", args, 1);
+    assert!(returned(&result, true));
+    assert_eq!(sim.view(|staking| staking.nft_token("0".to_string())).unwrap().owner_id, RECEIVER);
+    let receiver = sim.account_info(RECEIVER);
+    assert_eq!((receiver.stake_balance, receiver.locked_balance, receiver.boost_balance), (600 * ONE_TOKEN, 600 * ONE_TOKEN, 150 * ONE_TOKEN));
+    assert_eq!(sim.account_info(ALICE).stake_balance, 400 * ONE_TOKEN);
+    assert_eq!(sim.view(|staking| staking.get_total_boost()).0, 150 * ONE_TOKEN);
+}
+
+#[test]
+fn test_airdrop_stake_from_funded_balance() {
+    let mut sim = Simulation::new();
+    sim.create_user(ALICE, ONE_TOKEN);
+    let args = json!({ "receiver_id": STAKING, "amount": U128(500 * ONE_TOKEN), "msg": r#"{"action":"fund_airdrop"}"# });
+    assert!(is_success(&sim.call(OWNER, FT, "ft_transfer_call", args, 1)));
+    assert_eq!(sim.ft_balance_of(STAKING), REWARD_RESERVE + 500 * ONE_TOKEN);
+
+    // Other senders cannot fund it, their tokens are refunded
+    sim.create_user(BOB, 100 * ONE_TOKEN);
+    let args = json!({ "receiver_id": STAKING, "amount": U128(100 * ONE_TOKEN), "msg": r#"{"action":"fund_airdrop"}"# });
+    sim.call(BOB, FT, "ft_transfer_call", args, 1);
+    assert_eq!(sim.ft_balance_of(BOB), 100 * ONE_TOKEN);
+
+    let credits = vec![AirdropCredit { account_id: ALICE.to_string(), amount: U128(300 * ONE_TOKEN) }];
+    let result = sim.call(OWNER, STAKING, "airdrop_stake", json!({ "credits": credits }), 0);
+    assert!(returned(&result, 1u64));
+    assert_eq!(sim.account_info(ALICE).stake_balance, 300 * ONE_TOKEN);
+    assert_eq!(sim.pool_info().total_stake_balance.0, 300 * ONE_TOKEN);
+
+    // The uncredited balance is a liability as well
+    let result = sim.call(OWNER, STAKING, "check_invariants", json!({}), 0);
+    assert!(returned(&result, U128(REWARD_RESERVE)));
+}
+
+#[test]
+fn test_close_account() {
+    let mut sim = Simulation::new();
+    sim.create_user(ALICE, 500 * ONE_TOKEN);
+    assert!(is_success(&sim.stake(ALICE, 500 * ONE_TOKEN)));
+    sim.advance(100, 0);
+
+    // The stake is unstaked first, and the account cannot be closed before the unlock epoch
+    assert!(is_success(&sim.call(ALICE, STAKING, "close_account", json!({}), 1)));
+    assert_eq!(sim.account_info(ALICE).unstake_balance, 500 * ONE_TOKEN);
+    assert!(!is_success(&sim.call(ALICE, STAKING, "close_account", json!({}), 1)));
+    sim.advance(100, 1);
+
+    // Alice cannot receive tokens, so the account is restored
+    let reward = 715 * 500 * ONE_TOKEN * 100 / 10u128.pow(9);
+    assert!(is_success(&sim.call(ALICE, FT, "storage_unregister", json!({}), 0)));
+    let result = sim.call(ALICE, STAKING, "close_account", json!({}), 1);
+    assert!(returned(&result, U128(0)));
+    let account = sim.account_info(ALICE);
+    assert_eq!((account.unstake_balance, account.reward), (500 * ONE_TOKEN, reward));
+    assert_eq!(sim.pool_info().total_unstaking.0, 500 * ONE_TOKEN);
+
+    assert!(is_success(&sim.call(ALICE, FT, "storage_deposit", json!({}), 0)));
+    let result = sim.call(ALICE, STAKING, "close_account", json!({}), 1);
+    assert!(returned(&result, U128(500 * ONE_TOKEN + reward)));
+    assert_eq!(sim.ft_balance_of(ALICE), 500 * ONE_TOKEN + reward);
+    assert!(!sim.view(|staking| staking.exist_account(ALICE.to_string())));
+    let pool = sim.pool_info();
+    assert_eq!((pool.total_unstaking.0, pool.total_withdrawn.0), (0, 500 * ONE_TOKEN));
+    assert_eq!(pool.total_paid_reward.0, reward);
+
+    // The invariants hold without the closed account
+    let result = sim.call(OWNER, STAKING, "check_invariants", json!({}), 0);
+    assert!(returned(&result, U128(REWARD_RESERVE - reward)));
+}